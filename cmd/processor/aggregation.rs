@@ -0,0 +1,1157 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use h3o::{CellIndex, LatLng, Resolution};
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use tdigest::TDigest;
+use tracing::{debug, warn};
+
+use crate::config::AggregationConfig;
+use crate::proto::DataPoint;
+use crate::validation::{ValidationFailure, ValidationReport};
+
+/// Window name for calendar days in each reading's local timezone
+const LOCAL_DAY_WINDOW: &str = "local_day";
+
+/// A tumbling window, named as configured (e.g. "1h")
+#[derive(Debug, Clone)]
+struct Window {
+    name: String,
+    size: WindowSize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum WindowSize {
+    /// Fixed length, aligned to the Unix epoch, so "1d" runs from UTC midnight
+    Fixed(i64),
+    /// From local midnight to local midnight in the reading's timezone, which
+    /// is 23 or 25 hours long on DST transition days
+    LocalDay,
+}
+
+/// The window instance a reading falls in
+struct WindowBounds {
+    start_ms: i64,
+    end_ms: i64,
+    local_date: Option<NaiveDate>,
+}
+
+impl Window {
+    fn bounds(&self, epoch_ms: i64, timezone: Option<Tz>) -> Option<WindowBounds> {
+        match self.size {
+            WindowSize::Fixed(size_ms) => {
+                let start_ms = epoch_ms.div_euclid(size_ms) * size_ms;
+                Some(WindowBounds {
+                    start_ms,
+                    end_ms: start_ms.saturating_add(size_ms),
+                    local_date: None,
+                })
+            }
+            WindowSize::LocalDay => {
+                let timezone = timezone?;
+                let date = DateTime::from_timestamp_millis(epoch_ms)?
+                    .with_timezone(&timezone)
+                    .date_naive();
+                Some(WindowBounds {
+                    start_ms: local_midnight(timezone, date),
+                    end_ms: local_midnight(timezone, date.succ_opt()?),
+                    local_date: Some(date),
+                })
+            }
+        }
+    }
+}
+
+/// Start of a local calendar day. Where a DST change skips midnight, the day
+/// starts at the first local time that exists.
+fn local_midnight(timezone: Tz, date: NaiveDate) -> i64 {
+    let mut time = date.and_time(NaiveTime::MIN);
+    loop {
+        if let Some(start) = timezone.from_local_datetime(&time).earliest() {
+            return start.timestamp_millis();
+        }
+        time += TimeDelta::minutes(15);
+    }
+}
+
+/// Readings of one variable within one H3 cell, from one source or, for
+/// spatial rollups, from all of them
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    source: Option<String>,
+    category: String,
+    variable: String,
+    cell: CellIndex,
+}
+
+/// Size of the t-digests estimating percentiles; larger is more accurate
+const DIGEST_SIZE: usize = 100;
+
+/// Running statistics over the readings in a window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowStats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub last: f64,
+    last_ms: i64,
+    pub units: String,
+    /// Mergeable sketch of the value distribution, for percentiles
+    digest: TDigest,
+}
+
+impl WindowStats {
+    fn new(point: &DataPoint) -> Self {
+        WindowStats {
+            count: 1,
+            min: point.value,
+            max: point.value,
+            sum: point.value,
+            last: point.value,
+            last_ms: point.epoch_ms,
+            units: point.units.clone(),
+            digest: {
+                let mut digest = TDigest::new_with_size(DIGEST_SIZE);
+                digest.push(point.value);
+                digest
+            },
+        }
+    }
+
+    fn add(&mut self, point: &DataPoint) {
+        self.count += 1;
+        self.min = self.min.min(point.value);
+        self.max = self.max.max(point.value);
+        self.sum += point.value;
+        self.digest.push(point.value);
+        // Out-of-order readings do not replace a later one
+        if point.epoch_ms >= self.last_ms {
+            self.last = point.value;
+            self.last_ms = point.epoch_ms;
+            self.units.clone_from(&point.units);
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    /// Statistics over the readings of several windows, whose digests have
+    /// been flushed
+    fn merged(parts: &[&WindowStats]) -> Self {
+        let latest = parts
+            .iter()
+            .max_by_key(|stats| stats.last_ms)
+            .expect("merging no window statistics");
+        WindowStats {
+            count: parts.iter().map(|stats| stats.count).sum(),
+            min: parts
+                .iter()
+                .map(|stats| stats.min)
+                .fold(f64::INFINITY, f64::min),
+            max: parts
+                .iter()
+                .map(|stats| stats.max)
+                .fold(f64::NEG_INFINITY, f64::max),
+            sum: parts.iter().map(|stats| stats.sum).sum(),
+            last: latest.last,
+            last_ms: latest.last_ms,
+            units: latest.units.clone(),
+            digest: TDigest::merge_digests(
+                parts.iter().map(|stats| stats.digest.clone()).collect(),
+            ),
+        }
+    }
+
+    /// Fold buffered values into the digest, before it is read or saved
+    fn flush(&mut self) {
+        self.digest.flush();
+    }
+}
+
+/// Statistics for a series over a window that has closed. A window updated
+/// by late readings is emitted again with a higher revision.
+#[derive(Debug, Clone)]
+pub struct WindowAggregate {
+    pub window: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    /// The calendar day of a local day window
+    pub local_date: Option<NaiveDate>,
+    /// `None` for a spatial rollup over all sources in the cell
+    pub source: Option<String>,
+    pub category: String,
+    pub variable: String,
+    pub cell: CellIndex,
+    pub stats: WindowStats,
+    /// Estimated percentiles as (field name, value), e.g. ("p95", 31.2)
+    pub percentiles: Vec<(String, f64)>,
+    pub revision: u32,
+}
+
+/// A reading that arrived after its window's allowed lateness had passed
+#[derive(Debug, Clone)]
+pub struct LateReading {
+    pub window: String,
+    /// How far the watermark was past the end of the window
+    pub lateness_ms: i64,
+    pub data_point: DataPoint,
+}
+
+#[derive(Debug)]
+struct OpenWindow {
+    start_ms: i64,
+    local_date: Option<NaiveDate>,
+    stats: WindowStats,
+    /// Revision of the last emitted aggregate, if the window has closed
+    revision: Option<u32>,
+}
+
+/// Event-time progress of a Kafka partition
+#[derive(Debug)]
+struct PartitionClock {
+    max_event_ms: i64,
+    last_seen: Instant,
+    /// Offset of the next message not yet reflected in the windows
+    next_offset: i64,
+}
+
+/// Everything needed to rebuild an [`Aggregator`] after a restart
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AggregatorState {
+    watermark: i64,
+    emitted_through: i64,
+    partitions: Vec<PartitionState>,
+    windows: Vec<WindowState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PartitionState {
+    partition: i32,
+    max_event_ms: i64,
+    next_offset: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WindowState {
+    window: String,
+    start_ms: i64,
+    end_ms: i64,
+    local_date: Option<NaiveDate>,
+    source: Option<String>,
+    category: String,
+    variable: String,
+    cell: u64,
+    stats: WindowStats,
+    revision: Option<u32>,
+}
+
+/// Accumulates readings into tumbling event-time windows per source,
+/// variable and H3 cell, and optionally into spatial rollups per variable and
+/// H3 cell at further resolutions regardless of source. Readings only go into
+/// the finest rollups; coarser ones are merged from those when emitted.
+///
+/// Each partition's watermark is the latest event time seen on it; the
+/// overall watermark is the lowest among partitions that are not idle, so a
+/// partition that is behind holds windows open. A window is emitted when the
+/// watermark passes its end, and kept for the allowed lateness so that late
+/// readings can still update it and emit a correction. Readings arriving
+/// after that are reported as late instead.
+pub struct Aggregator {
+    windows: Vec<Window>,
+    resolution: Resolution,
+    /// Finest spatial rollup resolution, the only one readings are added to
+    rollup_resolution: Option<Resolution>,
+    /// Coarser spatial rollup resolutions, merged from the finest on emission
+    coarser_rollups: Vec<Resolution>,
+    /// Percentiles to estimate, between 0 and 100
+    percentiles: Vec<f64>,
+    allowed_lateness_ms: i64,
+    idle_timeout: Duration,
+    max_clock_skew_ms: i64,
+    /// Open windows by end time, so the ones to close come first
+    open: BTreeMap<i64, BTreeMap<(usize, SeriesKey), OpenWindow>>,
+    /// Closed windows changed by late readings since they were last emitted
+    updated: BTreeSet<(i64, usize, SeriesKey)>,
+    partitions: HashMap<i32, PartitionClock>,
+    watermark: i64,
+    /// Windows ending at or before this have been emitted
+    emitted_through: i64,
+}
+
+impl Aggregator {
+    pub fn new(config: &AggregationConfig) -> Result<Self> {
+        let windows = config
+            .windows
+            .iter()
+            .map(|name| {
+                if name == LOCAL_DAY_WINDOW {
+                    return Ok(Window {
+                        name: name.clone(),
+                        size: WindowSize::LocalDay,
+                    });
+                }
+
+                let size_ms = parse_duration_ms("aggregation window", name)?;
+                if size_ms == 0 {
+                    return Err(anyhow!("Aggregation window {} is empty", name));
+                }
+                Ok(Window {
+                    name: name.clone(),
+                    size: WindowSize::Fixed(size_ms),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let resolution = Resolution::try_from(config.h3_resolution)
+            .map_err(|e| anyhow!("Invalid aggregation H3 resolution: {}", e))?;
+        let mut rollup_resolutions = config
+            .spatial_rollup_resolutions
+            .iter()
+            .map(|&resolution| {
+                Resolution::try_from(resolution)
+                    .map_err(|e| anyhow!("Invalid spatial rollup H3 resolution: {}", e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        rollup_resolutions.sort_unstable_by(|a, b| b.cmp(a));
+        rollup_resolutions.dedup();
+
+        if let Some(percentile) = config
+            .percentiles
+            .iter()
+            .find(|percentile| !(0.0..=100.0).contains(*percentile))
+        {
+            return Err(anyhow!(
+                "Percentile {} is not between 0 and 100",
+                percentile
+            ));
+        }
+
+        Ok(Aggregator {
+            windows,
+            resolution,
+            rollup_resolution: rollup_resolutions.first().copied(),
+            coarser_rollups: rollup_resolutions.into_iter().skip(1).collect(),
+            percentiles: config.percentiles.clone(),
+            allowed_lateness_ms: parse_duration_ms("allowed lateness", &config.allowed_lateness)?,
+            idle_timeout: Duration::from_millis(parse_duration_ms(
+                "idle partition timeout",
+                &config.idle_partition_timeout,
+            )? as u64),
+            max_clock_skew_ms: parse_duration_ms("max clock skew", &config.max_clock_skew)?,
+            open: BTreeMap::new(),
+            updated: BTreeSet::new(),
+            partitions: HashMap::new(),
+            watermark: i64::MIN,
+            emitted_through: i64::MIN,
+        })
+    }
+
+    /// Report a reading timestamped further ahead of the wall clock than the
+    /// configured skew, which `add` would refuse
+    pub fn check_event_time(&self, point: &DataPoint, report: &mut ValidationReport) {
+        let ahead_ms = point.epoch_ms.saturating_sub(Utc::now().timestamp_millis());
+        if ahead_ms > self.max_clock_skew_ms {
+            report.push(
+                ValidationFailure::new("event_time", "max_clock_skew", "epoch_ms")
+                    .observed(ahead_ms as f64)
+                    .expected(None, Some(self.max_clock_skew_ms as f64)),
+            );
+        }
+    }
+
+    /// Add a reading from the message at `offset` in `partition` to its
+    /// windows; local day windows need the reading's IANA timezone. Returns
+    /// the windows it came too late for. Readings from messages already
+    /// reflected in restored state are ignored, and readings too far in the
+    /// future are refused without moving the watermark.
+    pub fn add(
+        &mut self,
+        point: &DataPoint,
+        timezone: Option<&str>,
+        partition: i32,
+        offset: i64,
+    ) -> Result<Vec<LateReading>> {
+        if self
+            .partitions
+            .get(&partition)
+            .is_some_and(|clock| offset < clock.next_offset)
+        {
+            debug!(
+                "Partition {} offset {} is already aggregated",
+                partition, offset
+            );
+            return Ok(Vec::new());
+        }
+
+        let now_ms = Utc::now().timestamp_millis();
+        if point.epoch_ms.saturating_sub(now_ms) > self.max_clock_skew_ms {
+            return Err(anyhow!(
+                "Event time {} is more than {} ms ahead of the clock",
+                point.epoch_ms,
+                self.max_clock_skew_ms
+            ));
+        }
+
+        let location = LatLng::new(point.lat, point.lon)
+            .map_err(|e| anyhow!("Cannot aggregate point without valid coordinates: {}", e))?;
+        let mut series = vec![SeriesKey {
+            source: Some(point.source.clone()),
+            category: point.category.clone(),
+            variable: point.variable.clone(),
+            cell: location.to_cell(self.resolution),
+        }];
+        if let Some(resolution) = self.rollup_resolution {
+            series.push(SeriesKey {
+                source: None,
+                category: point.category.clone(),
+                variable: point.variable.clone(),
+                cell: location.to_cell(resolution),
+            });
+        }
+
+        let timezone = timezone.and_then(|name| match name.parse::<Tz>() {
+            Ok(timezone) => Some(timezone),
+            Err(e) => {
+                debug!("Unknown timezone {}: {}", name, e);
+                None
+            }
+        });
+
+        let mut late = Vec::new();
+        for (index, window) in self.windows.iter().enumerate() {
+            let Some(bounds) = window.bounds(point.epoch_ms, timezone) else {
+                debug!(
+                    "No {} window for point from {} without a timezone",
+                    window.name, point.source
+                );
+                continue;
+            };
+            let end_ms = bounds.end_ms;
+            if end_ms.saturating_add(self.allowed_lateness_ms) <= self.watermark {
+                late.push(LateReading {
+                    window: window.name.clone(),
+                    lateness_ms: self.watermark - end_ms,
+                    data_point: point.clone(),
+                });
+                continue;
+            }
+
+            let open = self.open.entry(end_ms).or_default();
+            for key in &series {
+                open.entry((index, key.clone()))
+                    .and_modify(|open| open.stats.add(point))
+                    .or_insert_with(|| OpenWindow {
+                        start_ms: bounds.start_ms,
+                        local_date: bounds.local_date,
+                        stats: WindowStats::new(point),
+                        revision: None,
+                    });
+                if end_ms <= self.emitted_through {
+                    self.updated.insert((end_ms, index, key.clone()));
+                }
+            }
+        }
+
+        self.advance_watermark(partition, offset, point.epoch_ms);
+        Ok(late)
+    }
+
+    /// Emit windows the watermark has passed and corrections to windows
+    /// updated since, then forget windows past their allowed lateness
+    pub fn take_closed(&mut self) -> Vec<WindowAggregate> {
+        let mut due = std::mem::take(&mut self.updated);
+
+        if self.watermark > self.emitted_through {
+            let newly_closed = self
+                .open
+                .range(self.emitted_through.saturating_add(1)..=self.watermark);
+            for (&end_ms, series) in newly_closed {
+                due.extend(
+                    series
+                        .keys()
+                        .map(|(index, key)| (end_ms, *index, key.clone())),
+                );
+            }
+            self.emitted_through = self.watermark;
+        }
+
+        self.merge_rollups(&mut due);
+
+        let mut closed = Vec::new();
+        for (end_ms, index, key) in due {
+            if let Some(open) = self
+                .open
+                .get_mut(&end_ms)
+                .and_then(|series| series.get_mut(&(index, key.clone())))
+            {
+                closed.push(emit(
+                    &self.windows[index],
+                    &self.percentiles,
+                    end_ms,
+                    &key,
+                    open,
+                ));
+            }
+        }
+
+        while let Some(entry) = self.open.first_entry() {
+            if entry.key().saturating_add(self.allowed_lateness_ms) > self.watermark {
+                break;
+            }
+            entry.remove();
+        }
+
+        closed
+    }
+
+    /// Recompute the coarser spatial rollups over the finest ones in `due`,
+    /// and add them to `due`. Their statistics and t-digests are merged from
+    /// the finest rollups within each coarser cell.
+    fn merge_rollups(&mut self, due: &mut BTreeSet<(i64, usize, SeriesKey)>) {
+        let Some(finest) = self.rollup_resolution else {
+            return;
+        };
+
+        let mut wanted: BTreeMap<i64, BTreeSet<(usize, SeriesKey)>> = BTreeMap::new();
+        for (end_ms, index, key) in due.iter() {
+            if key.source.is_some() {
+                continue;
+            }
+            for &resolution in &self.coarser_rollups {
+                if let Some(parent) = key.cell.parent(resolution) {
+                    let parent = SeriesKey {
+                        cell: parent,
+                        ..key.clone()
+                    };
+                    wanted.entry(*end_ms).or_default().insert((*index, parent));
+                }
+            }
+        }
+
+        for (end_ms, wanted) in wanted {
+            let Some(series) = self.open.get_mut(&end_ms) else {
+                continue;
+            };
+
+            // Digests can only be merged once their buffered values are folded in
+            for ((_, key), open) in series.iter_mut() {
+                if key.source.is_none() && key.cell.resolution() == finest {
+                    open.stats.flush();
+                }
+            }
+
+            let mut children: BTreeMap<(usize, SeriesKey), Vec<&OpenWindow>> = BTreeMap::new();
+            for ((index, key), open) in series.iter() {
+                if key.source.is_some() || key.cell.resolution() != finest {
+                    continue;
+                }
+                for &resolution in &self.coarser_rollups {
+                    let Some(parent) = key.cell.parent(resolution) else {
+                        continue;
+                    };
+                    let parent = (
+                        *index,
+                        SeriesKey {
+                            cell: parent,
+                            ..key.clone()
+                        },
+                    );
+                    if wanted.contains(&parent) {
+                        children.entry(parent).or_default().push(open);
+                    }
+                }
+            }
+
+            let merged: Vec<_> = children
+                .into_iter()
+                .map(|(parent, children)| {
+                    let stats: Vec<&WindowStats> =
+                        children.iter().map(|child| &child.stats).collect();
+                    let window = OpenWindow {
+                        start_ms: children[0].start_ms,
+                        local_date: children[0].local_date,
+                        stats: WindowStats::merged(&stats),
+                        revision: None,
+                    };
+                    (parent, window)
+                })
+                .collect();
+            for ((index, key), window) in merged {
+                due.insert((end_ms, index, key.clone()));
+                match series.entry((index, key)) {
+                    Entry::Occupied(mut entry) => entry.get_mut().stats = window.stats,
+                    Entry::Vacant(entry) => {
+                        entry.insert(window);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of windows still accumulating readings
+    pub fn open_windows(&self) -> usize {
+        self.open.values().map(BTreeMap::len).sum()
+    }
+
+    /// Snapshot of the open windows and partition progress
+    pub fn state(&self) -> AggregatorState {
+        let mut windows = Vec::new();
+        for (&end_ms, series) in &self.open {
+            for ((index, key), open) in series {
+                windows.push(WindowState {
+                    window: self.windows[*index].name.clone(),
+                    start_ms: open.start_ms,
+                    end_ms,
+                    local_date: open.local_date,
+                    source: key.source.clone(),
+                    category: key.category.clone(),
+                    variable: key.variable.clone(),
+                    cell: u64::from(key.cell),
+                    stats: {
+                        let mut stats = open.stats.clone();
+                        stats.flush();
+                        stats
+                    },
+                    revision: open.revision,
+                });
+            }
+        }
+
+        AggregatorState {
+            watermark: self.watermark,
+            emitted_through: self.emitted_through,
+            partitions: self
+                .partitions
+                .iter()
+                .map(|(&partition, clock)| PartitionState {
+                    partition,
+                    max_event_ms: clock.max_event_ms,
+                    next_offset: clock.next_offset,
+                })
+                .collect(),
+            windows,
+        }
+    }
+
+    /// Replace the current state with a snapshot. Windows whose length is no
+    /// longer configured are dropped.
+    pub fn restore(&mut self, state: AggregatorState) {
+        let now = Instant::now();
+        self.watermark = state.watermark;
+        self.emitted_through = state.emitted_through;
+        self.partitions = state
+            .partitions
+            .into_iter()
+            .map(|partition| {
+                let clock = PartitionClock {
+                    max_event_ms: partition.max_event_ms,
+                    last_seen: now,
+                    next_offset: partition.next_offset,
+                };
+                (partition.partition, clock)
+            })
+            .collect();
+        self.open.clear();
+        self.updated.clear();
+
+        for window in state.windows {
+            let Some(index) = self.windows.iter().position(|w| w.name == window.window) else {
+                warn!(
+                    "Dropping checkpointed {} window for {} which is no longer configured",
+                    window.window, window.variable
+                );
+                continue;
+            };
+            let Ok(cell) = CellIndex::try_from(window.cell) else {
+                warn!(
+                    "Dropping checkpointed window with invalid H3 cell {}",
+                    window.cell
+                );
+                continue;
+            };
+
+            let series = SeriesKey {
+                source: window.source,
+                category: window.category,
+                variable: window.variable,
+                cell,
+            };
+            let open = OpenWindow {
+                start_ms: window.start_ms,
+                local_date: window.local_date,
+                stats: window.stats,
+                revision: window.revision,
+            };
+            self.open
+                .entry(window.end_ms)
+                .or_default()
+                .insert((index, series), open);
+        }
+    }
+
+    fn advance_watermark(&mut self, partition: i32, offset: i64, epoch_ms: i64) {
+        let now = Instant::now();
+        let clock = self.partitions.entry(partition).or_insert(PartitionClock {
+            max_event_ms: epoch_ms,
+            last_seen: now,
+            next_offset: offset,
+        });
+        clock.max_event_ms = clock.max_event_ms.max(epoch_ms);
+        clock.last_seen = now;
+        clock.next_offset = offset + 1;
+
+        // The current partition is never idle, so there is always a minimum
+        let active_min = self
+            .partitions
+            .values()
+            .filter(|clock| now.duration_since(clock.last_seen) < self.idle_timeout)
+            .map(|clock| clock.max_event_ms)
+            .min();
+        if let Some(watermark) = active_min {
+            self.watermark = self.watermark.max(watermark);
+        }
+    }
+}
+
+fn emit(
+    window: &Window,
+    percentiles: &[f64],
+    end_ms: i64,
+    series: &SeriesKey,
+    open: &mut OpenWindow,
+) -> WindowAggregate {
+    let revision = open.revision.map_or(0, |revision| revision + 1);
+    open.revision = Some(revision);
+
+    open.stats.flush();
+    let percentiles = percentiles
+        .iter()
+        .filter_map(|&percentile| {
+            let value = open.stats.digest.estimate_quantile(percentile / 100.0)?;
+            Some((format!("p{percentile}"), value))
+        })
+        .collect();
+
+    WindowAggregate {
+        window: window.name.clone(),
+        start_ms: open.start_ms,
+        end_ms,
+        local_date: open.local_date,
+        source: series.source.clone(),
+        category: series.category.clone(),
+        variable: series.variable.clone(),
+        cell: series.cell,
+        stats: open.stats.clone(),
+        percentiles,
+        revision,
+    }
+}
+
+fn parse_duration_ms(what: &str, value: &str) -> Result<i64> {
+    let duration = humantime::parse_duration(value)
+        .map_err(|e| anyhow!("Invalid {} {}: {}", what, value, e))?;
+    Ok(i64::try_from(duration.as_millis()).unwrap_or(i64::MAX))
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use crate::config::AggregationConfig;
+    use crate::proto::DataPoint;
+
+    /// 2024-01-01T00:00:00Z
+    pub const T0: i64 = 1_704_067_200_000;
+    pub const MINUTE: i64 = 60_000;
+    pub const HOUR: i64 = 60 * MINUTE;
+
+    pub fn config(windows: &[&str]) -> AggregationConfig {
+        AggregationConfig {
+            windows: windows.iter().map(ToString::to_string).collect(),
+            h3_resolution: 5,
+            spatial_rollup_resolutions: Vec::new(),
+            percentiles: vec![50.0],
+            allowed_lateness: "10m".to_string(),
+            idle_partition_timeout: "1m".to_string(),
+            max_clock_skew: "5m".to_string(),
+            checkpoint_path: None,
+        }
+    }
+
+    pub fn reading(source: &str, value: f64, epoch_ms: i64) -> DataPoint {
+        DataPoint {
+            source: source.to_string(),
+            category: "environmental".to_string(),
+            variable: "temperature".to_string(),
+            units: "celsius".to_string(),
+            value,
+            epoch_ms,
+            lat: 51.5,
+            lon: -0.1,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::*;
+    use super::*;
+
+    /// Add readings to partition 0 at consecutive offsets from `offset`
+    fn add_all(aggregator: &mut Aggregator, offset: i64, readings: &[DataPoint]) {
+        for (i, reading) in readings.iter().enumerate() {
+            let late = aggregator.add(reading, None, 0, offset + i as i64).unwrap();
+            assert!(late.is_empty());
+        }
+    }
+
+    #[test]
+    fn emits_window_statistics_once_the_watermark_passes_its_end() {
+        let mut aggregator = Aggregator::new(&config(&["1h"])).unwrap();
+        add_all(
+            &mut aggregator,
+            0,
+            &[
+                reading("station", 10.0, T0 + MINUTE),
+                reading("station", 14.0, T0 + 30 * MINUTE),
+                // Out of order, so not the last reading
+                reading("station", 12.0, T0 + 20 * MINUTE),
+            ],
+        );
+        assert!(aggregator.take_closed().is_empty());
+        assert_eq!(aggregator.open_windows(), 1);
+
+        add_all(&mut aggregator, 3, &[reading("station", 20.0, T0 + HOUR)]);
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 1);
+
+        let aggregate = &closed[0];
+        assert_eq!(aggregate.window, "1h");
+        assert_eq!((aggregate.start_ms, aggregate.end_ms), (T0, T0 + HOUR));
+        assert_eq!(aggregate.source.as_deref(), Some("station"));
+        assert_eq!(aggregate.revision, 0);
+        let stats = &aggregate.stats;
+        assert_eq!(stats.count, 3);
+        assert_eq!((stats.min, stats.max, stats.sum), (10.0, 14.0, 36.0));
+        assert_eq!(stats.mean(), 12.0);
+        assert_eq!(stats.last, 14.0);
+        assert_eq!(aggregate.percentiles[0].0, "p50");
+
+        // Each window is emitted once
+        assert!(aggregator.take_closed().is_empty());
+    }
+
+    #[test]
+    fn keeps_separate_windows_per_length_and_source() {
+        let mut aggregator = Aggregator::new(&config(&["1h", "1d"])).unwrap();
+        add_all(
+            &mut aggregator,
+            0,
+            &[
+                reading("station", 10.0, T0 + MINUTE),
+                reading("buoy", 8.0, T0 + 2 * MINUTE),
+                reading("station", 11.0, T0 + 2 * HOUR),
+            ],
+        );
+        assert_eq!(aggregator.open_windows(), 5);
+
+        let closed = aggregator.take_closed();
+        let mut emitted: Vec<(&str, Option<&str>, u64)> = closed
+            .iter()
+            .map(|aggregate| {
+                (
+                    aggregate.window.as_str(),
+                    aggregate.source.as_deref(),
+                    aggregate.stats.count,
+                )
+            })
+            .collect();
+        emitted.sort();
+        assert_eq!(
+            emitted,
+            [("1h", Some("buoy"), 1), ("1h", Some("station"), 1)]
+        );
+    }
+
+    #[test]
+    fn watermark_follows_the_slowest_active_partition() {
+        let mut aggregator = Aggregator::new(&config(&["1h"])).unwrap();
+        aggregator
+            .add(&reading("station", 10.0, T0 + 10 * MINUTE), None, 0, 0)
+            .unwrap();
+        aggregator
+            .add(&reading("buoy", 8.0, T0 + 2 * HOUR), None, 1, 0)
+            .unwrap();
+        assert_eq!(aggregator.watermark, T0 + 10 * MINUTE);
+        assert!(aggregator.take_closed().is_empty());
+
+        aggregator
+            .add(&reading("station", 11.0, T0 + HOUR + MINUTE), None, 0, 1)
+            .unwrap();
+        assert_eq!(aggregator.watermark, T0 + HOUR + MINUTE);
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].end_ms, T0 + HOUR);
+
+        // Messages already reflected in the windows are skipped
+        aggregator
+            .add(&reading("station", 99.0, T0 + 5 * MINUTE), None, 0, 1)
+            .unwrap();
+        let first = aggregator.open[&(T0 + HOUR)].values().next().unwrap();
+        assert_eq!(first.stats.count, 1);
+    }
+
+    #[test]
+    fn idle_partitions_stop_holding_back_the_watermark() {
+        let mut config = config(&["1h"]);
+        config.idle_partition_timeout = "20ms".to_string();
+        let mut aggregator = Aggregator::new(&config).unwrap();
+        aggregator
+            .add(&reading("buoy", 8.0, T0 + 5 * MINUTE), None, 1, 0)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+
+        aggregator
+            .add(&reading("station", 10.0, T0 + 2 * HOUR), None, 0, 0)
+            .unwrap();
+        assert_eq!(aggregator.watermark, T0 + 2 * HOUR);
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].source.as_deref(), Some("buoy"));
+    }
+
+    #[test]
+    fn late_readings_revise_windows_until_the_allowed_lateness_passes() {
+        let mut aggregator = Aggregator::new(&config(&["1h"])).unwrap();
+        add_all(
+            &mut aggregator,
+            0,
+            &[
+                reading("station", 10.0, T0 + 5 * MINUTE),
+                reading("station", 30.0, T0 + HOUR + 5 * MINUTE),
+            ],
+        );
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!((closed[0].revision, closed[0].stats.count), (0, 1));
+
+        // 5 minutes late, within the allowed 10
+        add_all(
+            &mut aggregator,
+            2,
+            &[reading("station", 20.0, T0 + 30 * MINUTE)],
+        );
+        let revised = aggregator.take_closed();
+        assert_eq!(revised.len(), 1);
+        assert_eq!((revised[0].revision, revised[0].stats.count), (1, 2));
+        assert_eq!(revised[0].stats.max, 20.0);
+        assert!(aggregator.take_closed().is_empty());
+
+        add_all(
+            &mut aggregator,
+            3,
+            &[reading("station", 31.0, T0 + HOUR + 15 * MINUTE)],
+        );
+        let late = aggregator
+            .add(&reading("station", 40.0, T0 + 40 * MINUTE), None, 0, 4)
+            .unwrap();
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0].window, "1h");
+        assert_eq!(late[0].lateness_ms, 15 * MINUTE);
+        assert!(aggregator.take_closed().is_empty());
+    }
+
+    #[test]
+    fn refuses_readings_too_far_ahead_of_the_clock() {
+        let mut aggregator = Aggregator::new(&config(&["1h"])).unwrap();
+        let now = Utc::now().timestamp_millis();
+        let future = reading("station", 10.0, now + HOUR);
+        let soon = reading("station", 10.0, now + MINUTE);
+
+        let mut report = ValidationReport::default();
+        aggregator.check_event_time(&future, &mut report);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].check, "max_clock_skew");
+        assert!(report.is_rejected());
+
+        assert!(aggregator.add(&future, None, 0, 0).is_err());
+        assert_eq!(aggregator.watermark, i64::MIN);
+        assert_eq!(aggregator.open_windows(), 0);
+
+        let mut report = ValidationReport::default();
+        aggregator.check_event_time(&soon, &mut report);
+        assert!(report.failures.is_empty());
+        aggregator.add(&soon, None, 0, 1).unwrap();
+        assert_eq!(aggregator.watermark, now + MINUTE);
+    }
+
+    #[test]
+    fn coarser_rollups_merge_the_finest_ones() {
+        let mut config = config(&["1h"]);
+        config.spatial_rollup_resolutions = vec![3, 5];
+        let mut aggregator = Aggregator::new(&config).unwrap();
+
+        let station = reading("station", 12.0, T0 + 2 * MINUTE);
+        // In another fine cell within the same coarse cell
+        let station_cell = LatLng::new(station.lat, station.lon)
+            .unwrap()
+            .to_cell(Resolution::Five);
+        let buoy_cell = station_cell
+            .parent(Resolution::Three)
+            .unwrap()
+            .children(Resolution::Five)
+            .find(|cell| *cell != station_cell)
+            .unwrap();
+        let mut buoy = reading("buoy", 8.0, T0 + MINUTE);
+        let centre = LatLng::from(buoy_cell);
+        (buoy.lat, buoy.lon) = (centre.lat(), centre.lng());
+
+        add_all(&mut aggregator, 0, &[station, buoy]);
+        // Per source and finest rollup windows only
+        assert_eq!(aggregator.open_windows(), 4);
+
+        add_all(
+            &mut aggregator,
+            2,
+            &[reading("station", 20.0, T0 + HOUR + MINUTE)],
+        );
+        let rollups = |closed: &[WindowAggregate], resolution| -> Vec<WindowAggregate> {
+            closed
+                .iter()
+                .filter(|aggregate| {
+                    aggregate.source.is_none() && aggregate.cell.resolution() == resolution
+                })
+                .cloned()
+                .collect()
+        };
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 5);
+        assert_eq!(rollups(&closed, Resolution::Five).len(), 2);
+        let coarse = rollups(&closed, Resolution::Three);
+        assert_eq!(coarse.len(), 1);
+        let stats = &coarse[0].stats;
+        assert_eq!((stats.count, stats.min, stats.max), (2, 8.0, 12.0));
+        assert_eq!(stats.last, 12.0);
+        assert_eq!(coarse[0].revision, 0);
+        let p50 = coarse[0].percentiles[0].1;
+        assert!((8.0..=12.0).contains(&p50));
+
+        // A late reading revises its finest rollup and the coarser one over it
+        add_all(
+            &mut aggregator,
+            3,
+            &[reading("station", 4.0, T0 + 30 * MINUTE)],
+        );
+        let revised = aggregator.take_closed();
+        assert_eq!(revised.len(), 3);
+        assert_eq!(rollups(&revised, Resolution::Five).len(), 1);
+        let coarse = rollups(&revised, Resolution::Three);
+        assert_eq!((coarse[0].stats.count, coarse[0].stats.min), (3, 4.0));
+        assert_eq!(coarse[0].revision, 1);
+    }
+
+    #[test]
+    fn rejects_empty_and_invalid_windows() {
+        assert!(Aggregator::new(&config(&["0s"])).is_err());
+        assert!(Aggregator::new(&config(&["fortnightly"])).is_err());
+    }
+
+    /// UTC milliseconds of a date and time
+    fn utc(date: &str, time: &str) -> i64 {
+        format!("{date}T{time}Z")
+            .parse::<DateTime<Utc>>()
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    fn local_day(epoch_ms: i64, timezone: &str) -> WindowBounds {
+        let window = Window {
+            name: "local_day".to_string(),
+            size: WindowSize::LocalDay,
+        };
+        window.bounds(epoch_ms, timezone.parse().ok()).unwrap()
+    }
+
+    #[test]
+    fn local_days_run_from_local_midnight() {
+        let bounds = local_day(utc("2024-07-01", "12:00:00"), "Europe/Paris");
+        assert_eq!(bounds.start_ms, utc("2024-06-30", "22:00:00"));
+        assert_eq!(bounds.end_ms - bounds.start_ms, 24 * HOUR);
+        assert_eq!(bounds.local_date, NaiveDate::from_ymd_opt(2024, 7, 1));
+
+        // 23:30 in New York is already the next day in UTC
+        let bounds = local_day(utc("2024-01-02", "04:30:00"), "America/New_York");
+        assert_eq!(bounds.local_date, NaiveDate::from_ymd_opt(2024, 1, 1));
+        assert_eq!(bounds.start_ms, utc("2024-01-01", "05:00:00"));
+    }
+
+    #[test]
+    fn local_days_skip_to_the_first_time_after_a_skipped_midnight() {
+        // Sao Paulo moved its clocks from 00:00 to 01:00 on 2018-11-04
+        let bounds = local_day(utc("2018-11-04", "12:00:00"), "America/Sao_Paulo");
+        assert_eq!(bounds.local_date, NaiveDate::from_ymd_opt(2018, 11, 4));
+        assert_eq!(bounds.start_ms, utc("2018-11-04", "03:00:00"));
+        assert_eq!(bounds.end_ms - bounds.start_ms, 23 * HOUR);
+
+        // The day before ends where that one starts
+        let before = local_day(utc("2018-11-03", "12:00:00"), "America/Sao_Paulo");
+        assert_eq!(before.end_ms, bounds.start_ms);
+        assert_eq!(before.end_ms - before.start_ms, 24 * HOUR);
+    }
+
+    #[test]
+    fn local_days_repeat_the_hour_before_a_fall_back_midnight() {
+        // Sao Paulo moved its clocks from 00:00 back to 23:00 on 2018-02-18
+        let bounds = local_day(utc("2018-02-17", "12:00:00"), "America/Sao_Paulo");
+        assert_eq!(bounds.local_date, NaiveDate::from_ymd_opt(2018, 2, 17));
+        assert_eq!(bounds.start_ms, utc("2018-02-17", "02:00:00"));
+        assert_eq!(bounds.end_ms, utc("2018-02-18", "03:00:00"));
+        assert_eq!(bounds.end_ms - bounds.start_ms, 25 * HOUR);
+
+        // Both 23:30s belong to the 17th
+        for time in ["01:30:00", "02:30:00"] {
+            let repeated = local_day(utc("2018-02-18", time), "America/Sao_Paulo");
+            assert_eq!(repeated.local_date, NaiveDate::from_ymd_opt(2018, 2, 17));
+        }
+    }
+
+    #[test]
+    fn local_day_windows_are_emitted_with_their_date() {
+        let mut aggregator = Aggregator::new(&config(&["local_day"])).unwrap();
+        let timezone = Some("America/Sao_Paulo");
+        let start = utc("2018-11-04", "03:00:00");
+        for (offset, epoch_ms) in [start + HOUR, start + 22 * HOUR].into_iter().enumerate() {
+            let late = aggregator
+                .add(
+                    &reading("station", 20.0, epoch_ms),
+                    timezone,
+                    0,
+                    offset as i64,
+                )
+                .unwrap();
+            assert!(late.is_empty());
+        }
+        // Readings without a geocoded timezone have no local day
+        aggregator
+            .add(&reading("buoy", 10.0, start + HOUR), None, 0, 2)
+            .unwrap();
+        assert_eq!(aggregator.open_windows(), 1);
+
+        aggregator
+            .add(&reading("station", 21.0, start + 23 * HOUR), timezone, 0, 3)
+            .unwrap();
+        let closed = aggregator.take_closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].window, "local_day");
+        assert_eq!(
+            (closed[0].start_ms, closed[0].end_ms),
+            (start, start + 23 * HOUR)
+        );
+        assert_eq!(closed[0].local_date, NaiveDate::from_ymd_opt(2018, 11, 4));
+        assert_eq!(closed[0].stats.count, 2);
+    }
+}
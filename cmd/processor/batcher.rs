@@ -0,0 +1,274 @@
+use rdkafka::message::OwnedMessage;
+use rdkafka::Message;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::aggregation::{LateReading, WindowAggregate};
+use crate::config::ProcessingConfig;
+use crate::kafka_consumer::MessagePosition;
+use crate::processor::{ProcessedPoint, RejectedPoint};
+
+/// Points and offsets accumulated from several Kafka messages, flushed to
+/// InfluxDB in a single request.
+#[derive(Default)]
+pub struct Batch {
+    pub points: Vec<ProcessedPoint>,
+    /// Each message that produced points, with the range of its points
+    pub messages: Vec<(OwnedMessage, Range<usize>)>,
+    /// Points that failed validation, written as `rejections`
+    pub rejections: Vec<RejectedPoint>,
+    /// Aggregation windows closed since the last flush
+    pub aggregates: Vec<WindowAggregate>,
+    /// Readings that arrived too late for their aggregation windows
+    pub late: Vec<LateReading>,
+    /// Lowest and highest offset handled per (topic, partition)
+    offsets: HashMap<(String, i32), (i64, i64)>,
+}
+
+impl Batch {
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Positions to store once the batch has been written. Partitions in
+    /// `failed` stop short of the offset given, the earliest message that was
+    /// neither written nor dead-lettered, so it is consumed again.
+    pub fn positions(&self, failed: &HashMap<(String, i32), i64>) -> Vec<MessagePosition> {
+        self.offsets
+            .iter()
+            .filter_map(|(key, &(first, last))| {
+                let offset = match failed.get(key) {
+                    Some(&failed) if failed <= first => return None,
+                    Some(&failed) => (failed - 1).min(last),
+                    None => last,
+                };
+                Some(MessagePosition {
+                    topic: key.0.clone(),
+                    partition: key.1,
+                    offset,
+                })
+            })
+            .collect()
+    }
+
+    fn track(&mut self, message: &OwnedMessage) {
+        let offset = message.offset();
+        let (first, last) = self
+            .offsets
+            .entry((message.topic().to_string(), message.partition()))
+            .or_insert((offset, offset));
+        *first = (*first).min(offset);
+        *last = (*last).max(offset);
+    }
+}
+
+pub struct PointBatcher {
+    batch_size: usize,
+    linger: Duration,
+    batch: Batch,
+    deadline: Option<Instant>,
+}
+
+impl PointBatcher {
+    pub fn new(config: &ProcessingConfig) -> Self {
+        PointBatcher {
+            batch_size: config.batch_size.max(1),
+            linger: Duration::from_millis(config.batch_linger_ms),
+            batch: Batch::default(),
+            deadline: None,
+        }
+    }
+
+    /// Record a message that was handled without producing points (e.g. it
+    /// was dead-lettered); its offset is stored with the rest of the batch.
+    pub fn track(&mut self, message: &OwnedMessage) {
+        self.batch.track(message);
+        self.deadline
+            .get_or_insert_with(|| Instant::now() + self.linger);
+    }
+
+    pub fn push(&mut self, message: OwnedMessage, points: Vec<ProcessedPoint>) {
+        self.track(&message);
+
+        let start = self.batch.points.len();
+        self.batch.points.extend(points);
+        let range = start..self.batch.points.len();
+        self.batch.messages.push((message, range));
+    }
+
+    pub fn push_rejected(&mut self, message: &OwnedMessage, rejected: RejectedPoint) {
+        self.track(message);
+        self.batch.rejections.push(rejected);
+    }
+
+    pub fn push_aggregates(&mut self, aggregates: Vec<WindowAggregate>, late: Vec<LateReading>) {
+        self.batch.aggregates.extend(aggregates);
+        self.batch.late.extend(late);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.batch.points.len() >= self.batch_size
+    }
+
+    /// When the current batch must be flushed, even if not full
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn take(&mut self) -> Batch {
+        self.deadline = None;
+        std::mem::take(&mut self.batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregation::test_support::{config, reading};
+    use crate::validation::ValidationReport;
+    use rdkafka::Timestamp;
+
+    fn batcher(batch_size: usize) -> PointBatcher {
+        PointBatcher::new(&ProcessingConfig {
+            enable_validation: true,
+            enable_enrichment: false,
+            enable_aggregation: false,
+            batch_size,
+            batch_linger_ms: 500,
+            validation_rules: Vec::new(),
+            aggregation: config(&["1h"]),
+        })
+    }
+
+    fn points(count: usize) -> Vec<ProcessedPoint> {
+        (0..count)
+            .map(|i| ProcessedPoint {
+                data_point: reading("buoy-1", i as f64, 0),
+                enriched_data: Default::default(),
+                quality: Default::default(),
+                original: None,
+            })
+            .collect()
+    }
+
+    fn rejected() -> RejectedPoint {
+        RejectedPoint {
+            data_point: reading("buoy-1", f64::NAN, 0),
+            report: ValidationReport::default(),
+        }
+    }
+
+    fn message(partition: i32, offset: i64) -> OwnedMessage {
+        OwnedMessage::new(
+            None,
+            None,
+            "data.raw".to_string(),
+            Timestamp::NotAvailable,
+            partition,
+            offset,
+            None,
+        )
+    }
+
+    fn positions(batch: &Batch, failed: &[(i32, i64)]) -> Vec<(i32, i64)> {
+        let failed = failed
+            .iter()
+            .map(|&(partition, offset)| (("data.raw".to_string(), partition), offset))
+            .collect();
+        let mut positions: Vec<(i32, i64)> = batch
+            .positions(&failed)
+            .into_iter()
+            .map(|position| (position.partition, position.offset))
+            .collect();
+        positions.sort_unstable();
+        positions
+    }
+
+    #[test]
+    fn positions_stop_short_of_messages_that_were_not_dead_lettered() {
+        let mut batch = Batch::default();
+        for (partition, offset) in [(0, 10), (0, 11), (0, 12), (1, 5), (2, 7)] {
+            batch.track(&message(partition, offset));
+        }
+
+        assert_eq!(positions(&batch, &[]), [(0, 12), (1, 5), (2, 7)]);
+        // Partition 1 keeps no position at all, so offset 5 is consumed again
+        assert_eq!(positions(&batch, &[(0, 11), (1, 5)]), [(0, 10), (2, 7)]);
+    }
+
+    #[test]
+    fn full_once_batch_size_points_are_pushed() {
+        let mut batcher = batcher(3);
+        batcher.push(message(0, 1), points(2));
+        batcher.push_rejected(&message(0, 2), rejected());
+        batcher.track(&message(0, 3));
+        // Only points count towards the batch size
+        assert!(!batcher.is_full());
+
+        batcher.push(message(0, 4), points(1));
+        assert!(batcher.is_full());
+    }
+
+    #[test]
+    fn deadline_is_set_by_the_first_message_after_a_take() {
+        let mut batcher = batcher(10);
+        assert_eq!(batcher.deadline(), None);
+
+        let before = Instant::now();
+        batcher.track(&message(0, 1));
+        let after = Instant::now();
+        let deadline = batcher.deadline().unwrap();
+        let linger = Duration::from_millis(500);
+        assert!(before + linger <= deadline && deadline <= after + linger);
+
+        // Later messages do not push the deadline back
+        batcher.push(message(0, 2), points(1));
+        batcher.push_rejected(&message(0, 3), rejected());
+        assert_eq!(batcher.deadline(), Some(deadline));
+    }
+
+    #[test]
+    fn take_hands_over_the_batch_and_starts_a_new_one() {
+        let mut batcher = batcher(2);
+        batcher.push(message(0, 1), points(2));
+        batcher.push_rejected(&message(0, 2), rejected());
+        batcher.push(message(1, 7), points(1));
+        assert!(batcher.is_full());
+
+        let batch = batcher.take();
+        assert_eq!(batch.points.len(), 3);
+        let ranges: Vec<_> = batch
+            .messages
+            .iter()
+            .map(|(message, range)| (message.offset(), range.clone()))
+            .collect();
+        assert_eq!(ranges, [(1, 0..2), (7, 2..3)]);
+        assert_eq!(batch.rejections.len(), 1);
+        assert_eq!(positions(&batch, &[]), [(0, 2), (1, 7)]);
+
+        assert!(!batcher.is_full());
+        assert_eq!(batcher.deadline(), None);
+        let empty = batcher.take();
+        assert!(empty.is_empty());
+        assert!(empty.points.is_empty() && empty.messages.is_empty());
+    }
+
+    #[test]
+    fn offsets_are_tracked_per_partition() {
+        let mut batcher = batcher(10);
+        // Whichever way a message was handled, its offset counts
+        batcher.track(&message(0, 12));
+        batcher.push(message(0, 10), points(1));
+        batcher.push_rejected(&message(0, 11), rejected());
+        batcher.push_rejected(&message(1, 3), rejected());
+        batcher.track(&message(2, 40));
+
+        let batch = batcher.take();
+        assert_eq!(positions(&batch, &[]), [(0, 12), (1, 3), (2, 40)]);
+        assert_eq!(positions(&batch, &[(0, 11)]), [(0, 10), (1, 3), (2, 40)]);
+        // Untracked partitions are left alone
+        assert_eq!(positions(&batch, &[(5, 1)]).len(), 3);
+    }
+}
@@ -0,0 +1,376 @@
+use anyhow::{anyhow, bail, Context, Result};
+use geo::{Contains, MultiPolygon, Point};
+use h3o::geom::{ContainmentMode, TilerBuilder};
+use h3o::{LatLng, Resolution};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use shapefile::dbase::FieldValue;
+
+use crate::config::{BoundaryKind, BoundaryLayerConfig};
+
+/// Resolution of the cells boundaries are bucketed by. Cells of about
+/// 12,000 km² keep even the largest countries and marine regions to a few
+/// thousand cells, while most cells lie entirely inside a single polygon.
+const CELL_RESOLUTION: Resolution = Resolution::Three;
+
+/// A polygon from a boundary layer and the codes it assigns
+struct Boundary {
+    area: MultiPolygon,
+    country: Option<Arc<str>>,
+    region: Option<Arc<str>>,
+    name: Option<Arc<str>>,
+}
+
+/// Codes and names assigned to a point by the boundary layers
+#[derive(Debug, Default)]
+pub struct BoundaryMatch {
+    /// ISO2 country code, from a country layer or else a marine one
+    pub country: Option<Arc<str>>,
+    pub country_name: Option<Arc<str>>,
+    /// Admin1 code
+    pub region: Option<Arc<str>>,
+    pub region_name: Option<Arc<str>>,
+    pub marine_region: Option<Arc<str>>,
+}
+
+/// Boundaries of one kind by the H3 cells covering them, as for geofences.
+/// A point in a cell lying entirely inside a boundary is in it; for cells
+/// on a boundary's edge its polygon decides.
+#[derive(Default)]
+struct BoundarySet {
+    boundaries: Vec<Boundary>,
+    /// Boundaries covering each cell, by index into `boundaries`, and
+    /// whether the cell lies entirely inside the boundary
+    cells: HashMap<u64, Vec<(u32, bool)>>,
+}
+
+impl BoundarySet {
+    fn add(&mut self, boundary: Boundary) -> Result<()> {
+        let index = u32::try_from(self.boundaries.len())?;
+        let tile = |mode| -> Result<Vec<u64>> {
+            let mut tiler = TilerBuilder::new(CELL_RESOLUTION)
+                .containment_mode(mode)
+                .build();
+            tiler.add_batch(boundary.area.0.iter().cloned())?;
+            Ok(tiler.into_coverage().map(u64::from).collect())
+        };
+
+        let inside: HashSet<u64> = tile(ContainmentMode::ContainsBoundary)?
+            .into_iter()
+            .collect();
+        for cell in tile(ContainmentMode::Covers)? {
+            self.cells
+                .entry(cell)
+                .or_default()
+                .push((index, inside.contains(&cell)));
+        }
+
+        self.boundaries.push(boundary);
+        Ok(())
+    }
+
+    /// The first boundary loaded containing the point
+    fn find(&self, cell: u64, point: Point) -> Option<&Boundary> {
+        self.cells
+            .get(&cell)?
+            .iter()
+            .map(|&(index, whole)| (&self.boundaries[index as usize], whole))
+            .find(|(boundary, whole)| *whole || boundary.area.contains(&point))
+            .map(|(boundary, _)| boundary)
+    }
+}
+
+/// Country, admin1 and marine boundary polygons, which are authoritative
+/// for country and region where they are configured. Unlike geonames
+/// places they also hold near borders and offshore.
+#[derive(Default)]
+pub struct Boundaries {
+    countries: BoundarySet,
+    admin1: BoundarySet,
+    marine: BoundarySet,
+}
+
+impl Boundaries {
+    pub fn load(layers: &[BoundaryLayerConfig]) -> Result<Self> {
+        let mut boundaries = Boundaries::default();
+        for layer in layers {
+            let loaded = load_layer(layer)
+                .with_context(|| format!("Failed to load boundary layer {}", layer.path))?;
+            let set = match layer.kind {
+                BoundaryKind::Country => &mut boundaries.countries,
+                BoundaryKind::Admin1 => &mut boundaries.admin1,
+                BoundaryKind::Marine => &mut boundaries.marine,
+            };
+            for boundary in loaded {
+                set.add(boundary)
+                    .with_context(|| format!("Invalid boundary in {}", layer.path))?;
+            }
+        }
+        Ok(boundaries)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.countries.boundaries.len()
+            + self.admin1.boundaries.len()
+            + self.marine.boundaries.len()
+    }
+
+    /// The codes of the first polygon of each kind containing the point
+    pub fn locate(&self, lat: f64, lng: f64) -> BoundaryMatch {
+        let mut found = BoundaryMatch::default();
+        let Ok(coord) = LatLng::new(lat, lng) else {
+            return found;
+        };
+        let cell = u64::from(coord.to_cell(CELL_RESOLUTION));
+        let point = Point::new(lng, lat);
+
+        if let Some(marine) = self.marine.find(cell, point) {
+            found.country = marine.country.clone();
+            found.marine_region = marine.name.clone();
+        }
+        if let Some(country) = self.countries.find(cell, point) {
+            found.country = country.country.clone();
+            found.country_name = country.name.clone();
+        }
+        // A region outside the country found above would be contradictory
+        if let Some(admin1) = self.admin1.find(cell, point).filter(|admin1| {
+            found.country.is_none() || admin1.country.is_none() || admin1.country == found.country
+        }) {
+            found.country = found.country.or_else(|| admin1.country.clone());
+            found.region = admin1.region.clone();
+            found.region_name = admin1.name.clone();
+        }
+        found
+    }
+}
+
+/// Polygon features of a GeoJSON file or Shapefile, with their text and
+/// number properties
+pub type Features = Vec<(MultiPolygon, HashMap<String, String>)>;
+
+fn load_layer(layer: &BoundaryLayerConfig) -> Result<Vec<Boundary>> {
+    match (&layer.kind, &layer.country_property, &layer.region_property) {
+        (BoundaryKind::Admin1, _, None) => bail!("admin1 layers need a region_property"),
+        (BoundaryKind::Country | BoundaryKind::Marine, None, _) => {
+            bail!("country and marine layers need a country_property")
+        }
+        _ => {}
+    }
+
+    let features = match Path::new(&layer.path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("shp") => read_shapefile(&layer.path)?,
+        _ => read_geojson(&layer.path)?,
+    };
+
+    let mut boundaries = Vec::new();
+    for (area, properties) in features {
+        if area.0.is_empty() {
+            continue;
+        }
+        let property = |name: &Option<String>| {
+            properties
+                .get(name.as_deref()?)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let mut country = property(&layer.country_property);
+        let mut region = property(&layer.region_property);
+        // Geonames-style admin1 codes such as `GB.ENG` carry the country too
+        if let Some((code_country, code)) = region.as_deref().and_then(|r| r.split_once('.')) {
+            country.get_or_insert_with(|| code_country.to_string());
+            region = Some(code.to_string());
+        }
+
+        boundaries.push(Boundary {
+            area,
+            country: country.map(Arc::from),
+            region: region.map(Arc::from),
+            name: property(&layer.name_property).map(Arc::from),
+        });
+    }
+    Ok(boundaries)
+}
+
+pub fn read_geojson(path: &str) -> Result<Features> {
+    let contents = std::fs::read_to_string(path)?;
+    let collection: geojson::FeatureCollection =
+        contents.parse::<geojson::GeoJson>()?.try_into()?;
+
+    let mut features: Features = Vec::new();
+    for feature in collection.features {
+        let Some(geometry) = feature.geometry else {
+            continue;
+        };
+        let area = match geo::Geometry::try_from(geometry.value)? {
+            geo::Geometry::Polygon(polygon) => MultiPolygon::new(vec![polygon]),
+            geo::Geometry::MultiPolygon(polygons) => polygons,
+            _ => continue,
+        };
+        let properties = feature
+            .properties
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, value)| match value {
+                serde_json::Value::String(value) => Some((name, value)),
+                serde_json::Value::Number(value) => Some((name, value.to_string())),
+                _ => None,
+            })
+            .collect();
+        features.push((area, properties));
+    }
+    Ok(features)
+}
+
+fn read_shapefile(path: &str) -> Result<Features> {
+    let shapes = shapefile::read_as::<_, shapefile::Polygon, shapefile::dbase::Record>(path)
+        .map_err(|e| anyhow!("{}", e))?;
+
+    Ok(shapes
+        .into_iter()
+        .map(|(polygon, record)| {
+            let properties = HashMap::<String, FieldValue>::from(record)
+                .into_iter()
+                .filter_map(|(name, value)| match value {
+                    FieldValue::Character(Some(value)) | FieldValue::Memo(value) => {
+                        Some((name, value))
+                    }
+                    FieldValue::Numeric(Some(value)) => Some((name, value.to_string())),
+                    _ => None,
+                })
+                .collect();
+            (MultiPolygon::from(polygon), properties)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::{coord, Rect};
+
+    /// Features given as (code, name, (min lng, min lat), (max lng, max lat))
+    type Squares<'a> = &'a [(&'a str, &'a str, (f64, f64), (f64, f64))];
+
+    const COUNTRIES: Squares = &[
+        ("AA", "Aland", (0.0, 40.0), (10.0, 50.0)),
+        ("BB", "Beland", (10.0, 40.0), (20.0, 50.0)),
+    ];
+    const ADMIN1: Squares = &[
+        ("AA.X1", "West Aland", (0.0, 40.0), (5.0, 50.0)),
+        ("BB.Y1", "Overlapping", (4.0, 40.0), (6.0, 50.0)),
+    ];
+    const MARINE: Squares = &[("AA", "Aland EEZ", (-10.0, 40.0), (0.0, 50.0))];
+
+    fn layer(kind: BoundaryKind, squares: Squares) -> BoundaryLayerConfig {
+        let features: Vec<_> = squares
+            .iter()
+            .map(|&(code, name, (lng0, lat0), (lng1, lat1))| {
+                serde_json::json!({
+                    "type": "Feature",
+                    "properties": { "code": code, "name": name },
+                    "geometry": {
+                        "type": "Polygon",
+                        "coordinates": [[[lng0, lat0], [lng1, lat0], [lng1, lat1], [lng0, lat1], [lng0, lat0]]]
+                    }
+                })
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!(
+            "boundaries-{:?}-{}.geojson",
+            kind,
+            std::process::id()
+        ));
+        let collection = serde_json::json!({ "type": "FeatureCollection", "features": features });
+        std::fs::write(&path, collection.to_string()).unwrap();
+
+        BoundaryLayerConfig {
+            path: path.to_str().unwrap().to_string(),
+            kind,
+            country_property: (kind != BoundaryKind::Admin1).then(|| "code".to_string()),
+            region_property: (kind == BoundaryKind::Admin1).then(|| "code".to_string()),
+            name_property: Some("name".to_string()),
+        }
+    }
+
+    fn boundaries() -> Boundaries {
+        let layers = [
+            layer(BoundaryKind::Country, COUNTRIES),
+            layer(BoundaryKind::Admin1, ADMIN1),
+            layer(BoundaryKind::Marine, MARINE),
+        ];
+        let boundaries = Boundaries::load(&layers).unwrap();
+        for layer in layers {
+            std::fs::remove_file(layer.path).unwrap();
+        }
+        boundaries
+    }
+
+    #[test]
+    fn locates_points_by_layer() {
+        let boundaries = boundaries();
+        assert_eq!(boundaries.len(), 5);
+
+        let inland = boundaries.locate(45.0, 2.0);
+        assert_eq!(inland.country.as_deref(), Some("AA"));
+        assert_eq!(inland.country_name.as_deref(), Some("Aland"));
+        assert_eq!(inland.region.as_deref(), Some("X1"));
+        assert_eq!(inland.region_name.as_deref(), Some("West Aland"));
+        assert!(inland.marine_region.is_none());
+
+        // Either side of a border running through the middle of a cell
+        assert_eq!(boundaries.locate(45.0, 9.99).country.as_deref(), Some("AA"));
+        assert_eq!(
+            boundaries.locate(45.0, 10.01).country.as_deref(),
+            Some("BB")
+        );
+
+        // The BB region is contradicted by the country
+        let overlap = boundaries.locate(45.0, 5.5);
+        assert_eq!(overlap.country.as_deref(), Some("AA"));
+        assert!(overlap.region.is_none());
+
+        let offshore = boundaries.locate(45.0, -5.0);
+        assert_eq!(offshore.country.as_deref(), Some("AA"));
+        assert_eq!(offshore.marine_region.as_deref(), Some("Aland EEZ"));
+        assert!(offshore.country_name.is_none());
+
+        for (lat, lng) in [(45.0, 25.0), (-45.0, 5.0), (f64::NAN, 5.0)] {
+            let nowhere = boundaries.locate(lat, lng);
+            assert!(nowhere.country.is_none() && nowhere.marine_region.is_none());
+        }
+    }
+
+    /// The code of the first square containing the point
+    fn scan(squares: Squares<'static>, point: Point) -> Option<&'static str> {
+        squares
+            .iter()
+            .find(|(_, _, min, max)| {
+                let rect = Rect::new(coord! { x: min.0, y: min.1 }, coord! { x: max.0, y: max.1 });
+                rect.to_polygon().contains(&point)
+            })
+            .map(|&(code, ..)| code)
+    }
+
+    #[test]
+    fn cells_find_the_same_boundaries_as_a_scan() {
+        let boundaries = boundaries();
+
+        for lat in (0..50).map(|i| 38.03 + 0.29 * i as f64) {
+            for lng in (0..90).map(|i| -12.01 + 0.37 * i as f64) {
+                let point = Point::new(lng, lat);
+                let expected = scan(COUNTRIES, point).or_else(|| scan(MARINE, point));
+                let found = boundaries.locate(lat, lng);
+                assert_eq!(found.country.as_deref(), expected, "({lat}, {lng})");
+            }
+        }
+    }
+}
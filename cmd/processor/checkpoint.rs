@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::aggregation::AggregatorState;
+
+/// File holding the aggregation state as of the last stored Kafka offsets, so
+/// a restarted processor resumes its open windows instead of losing them.
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(path: &str) -> Self {
+        Checkpoint {
+            path: PathBuf::from(path),
+        }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// The saved state, or `None` if nothing has been saved yet
+    pub fn load(&self) -> Result<Option<AggregatorState>> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(anyhow!(
+                    "Failed to read checkpoint {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            }
+        };
+
+        let state = serde_json::from_slice(&contents)
+            .with_context(|| format!("Invalid checkpoint {}", self.path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Durably replace the saved state
+    pub async fn save(&self, state: &AggregatorState) -> Result<()> {
+        let body = serde_json::to_vec(state)?;
+        let path = self.path.clone();
+        let temp_path = path.with_extension("tmp");
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = std::fs::File::create(&temp_path)?;
+            file.write_all(&body)?;
+            file.sync_all()?;
+            std::fs::rename(&temp_path, &path)?;
+            Ok(())
+        })
+        .await?
+        .with_context(|| format!("Failed to write checkpoint {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregation::test_support::{config, reading, HOUR, T0};
+    use crate::aggregation::Aggregator;
+
+    fn checkpoint(name: &str) -> (Checkpoint, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "emma-checkpoint-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        (Checkpoint::new(path.to_str().unwrap()), path)
+    }
+
+    fn aggregator() -> Aggregator {
+        Aggregator::new(&config(&["1h"])).unwrap()
+    }
+
+    #[tokio::test]
+    async fn restored_state_resumes_open_windows() {
+        let (checkpoint, path) = checkpoint("resume");
+        assert!(checkpoint.load().unwrap().is_none());
+
+        let mut before = aggregator();
+        before
+            .add(&reading("station", 10.0, T0 + 1000), None, 0, 0)
+            .unwrap();
+        before
+            .add(&reading("station", 20.0, T0 + 2000), None, 0, 1)
+            .unwrap();
+        checkpoint.save(&before.state()).await.unwrap();
+
+        let mut after = aggregator();
+        after.restore(checkpoint.load().unwrap().unwrap());
+        assert_eq!(after.open_windows(), 1);
+
+        after
+            .add(&reading("station", 30.0, T0 + HOUR), None, 0, 2)
+            .unwrap();
+        let closed = after.take_closed();
+        assert_eq!(closed.len(), 1);
+        let stats = &closed[0].stats;
+        assert_eq!((stats.count, stats.max, stats.last), (2, 20.0, 20.0));
+        assert_eq!(closed[0].percentiles.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readings_already_in_the_restored_state_are_skipped() {
+        let (checkpoint, path) = checkpoint("replay");
+        let mut before = aggregator();
+        before
+            .add(&reading("station", 10.0, T0 + 1000), None, 0, 0)
+            .unwrap();
+        before
+            .add(&reading("station", 20.0, T0 + 2000), None, 0, 1)
+            .unwrap();
+        before
+            .add(&reading("buoy", 5.0, T0 + 1000), None, 1, 40)
+            .unwrap();
+        checkpoint.save(&before.state()).await.unwrap();
+
+        // Kafka redelivers everything after the last stored offsets
+        let mut after = aggregator();
+        after.restore(checkpoint.load().unwrap().unwrap());
+        after
+            .add(&reading("station", 10.0, T0 + 1000), None, 0, 0)
+            .unwrap();
+        after
+            .add(&reading("station", 20.0, T0 + 2000), None, 0, 1)
+            .unwrap();
+        after
+            .add(&reading("station", 25.0, T0 + 3000), None, 0, 2)
+            .unwrap();
+        after
+            .add(&reading("buoy", 5.0, T0 + 1000), None, 1, 40)
+            .unwrap();
+        after
+            .add(&reading("buoy", 6.0, T0 + HOUR), None, 1, 41)
+            .unwrap();
+        after
+            .add(&reading("station", 30.0, T0 + HOUR), None, 0, 3)
+            .unwrap();
+
+        let mut counts: Vec<(Option<String>, u64)> = after
+            .take_closed()
+            .into_iter()
+            .map(|aggregate| (aggregate.source, aggregate.stats.count))
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            [
+                (Some("buoy".to_string()), 1),
+                (Some("station".to_string()), 3)
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_or_truncated_checkpoints_fail_to_load() {
+        let (checkpoint, path) = checkpoint("corrupt");
+        let mut aggregator = aggregator();
+        aggregator
+            .add(&reading("station", 10.0, T0 + 1000), None, 0, 0)
+            .unwrap();
+        checkpoint.save(&aggregator.state()).await.unwrap();
+        let saved = std::fs::read(&path).unwrap();
+
+        std::fs::write(&path, &saved[..saved.len() / 2]).unwrap();
+        let error = checkpoint.load().unwrap_err();
+        assert!(format!("{:#}", error).contains("Invalid checkpoint"));
+
+        std::fs::write(&path, b"{\"watermark\": 0}").unwrap();
+        assert!(checkpoint.load().is_err());
+
+        std::fs::write(&path, b"\x00\xff not json").unwrap();
+        assert!(checkpoint.load().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
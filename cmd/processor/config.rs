@@ -1,6 +1,11 @@
 use anyhow::Result;
-use config::{Config, ConfigError, Environment};
+use config::{Config, ConfigError, Environment, File};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Environment variable naming the config file, as an alternative to `--config`
+pub const CONFIG_FILE_ENV: &str = "PROCESSOR_CONFIG";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ProcessorConfig {
@@ -8,6 +13,10 @@ pub struct ProcessorConfig {
     pub processing: ProcessingConfig,
     pub influxdb: InfluxDbConfig,
     pub geocoder: GeocoderConfig,
+    pub geofences: GeofenceConfig,
+    pub units: UnitsConfig,
+    pub spool: SpoolConfig,
+    pub metrics: MetricsConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,6 +25,10 @@ pub struct KafkaConfig {
     pub group_id: String,
     pub topic: String,
     pub auto_offset_reset: String,
+    /// How often stored offsets are committed back to Kafka
+    pub commit_interval_ms: u64,
+    /// Topic receiving messages that could not be decoded, validated or written
+    pub dead_letter_topic: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -24,15 +37,160 @@ pub struct ProcessingConfig {
     pub enable_enrichment: bool,
     pub enable_aggregation: bool,
     pub batch_size: usize,
-    pub validation_rules: ValidationRules,
+    /// Longest time a partially filled batch waits before being flushed
+    pub batch_linger_ms: u64,
+    #[serde(default = "default_validation_rules")]
+    pub validation_rules: Vec<ValidationRule>,
+    pub aggregation: AggregationConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct ValidationRules {
-    pub temperature_min: f64,
-    pub temperature_max: f64,
-    pub humidity_min: f64,
-    pub humidity_max: f64,
+pub struct AggregationConfig {
+    /// Tumbling window lengths, e.g. "1h" or "1d" (UTC days), or "local_day"
+    /// for calendar days in each reading's geocoded timezone
+    pub windows: Vec<String>,
+    /// H3 resolution of the cells readings are grouped by
+    pub h3_resolution: u8,
+    /// H3 resolutions at which readings of a variable are also combined
+    /// across sources, written to `h3_rollups` for hex maps
+    #[serde(default)]
+    pub spatial_rollup_resolutions: Vec<u8>,
+    /// Percentiles estimated per window, e.g. 95 is written as `p95`
+    pub percentiles: Vec<f64>,
+    /// How long after the watermark passes a window late readings may still
+    /// correct it, e.g. "10m"
+    pub allowed_lateness: String,
+    /// Partitions without messages for this long no longer hold back the watermark
+    pub idle_partition_timeout: String,
+    /// How far ahead of the wall clock event times may be, e.g. "5m"; later
+    /// readings are rejected rather than moving the watermark into the future
+    pub max_clock_skew: String,
+    /// File the open windows are saved to before offsets are stored, once per
+    /// Kafka commit interval, so they survive restarts; unset to start from
+    /// empty windows every time
+    pub checkpoint_path: Option<String>,
+}
+
+/// Range checks for one kind of reading. A point is checked against the most
+/// specific rule matching its category and variable (`"*"` matches any
+/// variable), preferring rules that also pin down the source and/or units.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ValidationRule {
+    /// Reported with failures; defaults to `category.variable`
+    pub id: Option<String>,
+    pub category: String,
+    pub variable: String,
+    pub source: Option<String>,
+    pub units: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    pub non_negative: bool,
+    #[serde(default)]
+    pub positive: bool,
+    #[serde(default)]
+    pub integer_only: bool,
+    #[serde(default)]
+    pub allow_nan: bool,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub on_failure: FailureAction,
+}
+
+/// Whether a failed check rejects the point or is only reported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    #[default]
+    Error,
+}
+
+/// What happens to a point failing an error-level check: dropped, or kept
+/// and written with a `bad` quality-control flag
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureAction {
+    #[default]
+    Drop,
+    Flag,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+impl ValidationRule {
+    fn new(category: &str, variable: &str) -> Self {
+        ValidationRule {
+            id: None,
+            category: category.to_string(),
+            variable: variable.to_string(),
+            source: None,
+            units: None,
+            min: None,
+            max: None,
+            non_negative: false,
+            positive: false,
+            integer_only: false,
+            allow_nan: false,
+            severity: Severity::Error,
+            on_failure: FailureAction::Drop,
+        }
+    }
+
+    fn range(category: &str, variable: &str, min: f64, max: f64) -> Self {
+        ValidationRule {
+            min: Some(min),
+            max: Some(max),
+            ..Self::new(category, variable)
+        }
+    }
+
+    fn non_negative(category: &str, variable: &str) -> Self {
+        ValidationRule {
+            non_negative: true,
+            ..Self::new(category, variable)
+        }
+    }
+}
+
+/// Rules used when the config does not provide `processing.validation_rules`
+fn default_validation_rules() -> Vec<ValidationRule> {
+    vec![
+        ValidationRule::range("environmental", "temperature", -100.0, 100.0),
+        ValidationRule::range("environmental", "humidity", 0.0, 100.0),
+        ValidationRule::non_negative("environmental", "air_quality"),
+        ValidationRule::non_negative("environmental", "pm2.5"),
+        ValidationRule::non_negative("environmental", "pm10"),
+        ValidationRule::range("health", "heart_rate", 30.0, 250.0),
+        ValidationRule::range("health", "temperature", 35.0, 42.0),
+        ValidationRule::range("infrastructure", "temperature", -50.0, 200.0),
+        ValidationRule {
+            positive: true,
+            ..ValidationRule::new("infrastructure", "pressure")
+        },
+        ValidationRule::non_negative("infrastructure", "flow_rate"),
+        ValidationRule::non_negative("economic", "price"),
+        ValidationRule::non_negative("economic", "cost"),
+        ValidationRule::non_negative("economic", "revenue"),
+        ValidationRule {
+            integer_only: true,
+            ..ValidationRule::non_negative("social", "population")
+        },
+        ValidationRule {
+            integer_only: true,
+            ..ValidationRule::non_negative("social", "count")
+        },
+        ValidationRule::range("social", "percentage", 0.0, 100.0),
+        ValidationRule::range("social", "rate", 0.0, 100.0),
+    ]
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -44,37 +202,357 @@ pub struct InfluxDbConfig {
     pub token: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UnitsConfig {
+    /// Unit each variable is converted to; variables not listed keep the
+    /// units they arrive in
+    #[serde(default)]
+    pub canonical: HashMap<String, String>,
+    /// Extra spellings for known units, e.g. `"deg C": celsius`
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    pub unknown_units: UnknownUnitPolicy,
+}
+
+/// What happens to a point whose units are not known, or cannot be converted
+/// to the canonical unit for its variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownUnitPolicy {
+    /// Keep the point with its value and units unchanged
+    Accept,
+    /// Keep the point, flagged as suspect
+    Flag,
+    /// Reject the point as failing validation
+    Reject,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SpoolConfig {
+    /// Directory holding line protocol batches InfluxDB has not accepted yet
+    pub directory: String,
+    pub max_bytes: u64,
+    pub full_policy: SpoolFullPolicy,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+/// What to do when the spool reaches `max_bytes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpoolFullPolicy {
+    /// Stop consuming until replay frees up space
+    Block,
+    /// Discard the oldest spooled batches to make room
+    DropOldest,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MetricsConfig {
+    pub report_interval_ms: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GeocoderConfig {
-    pub geonames_file_path: String,
+    /// Geonames dump to index: allCountries, one of the cities500/1000/5000/
+    /// 15000 dumps or a country file, as plain text or a `.zip` or `.gz`
+    /// download
+    pub geonames_file_path: Option<String>,
+    /// Further dumps merged into the same index, e.g. several country files
+    #[serde(default)]
+    pub geonames_files: Vec<String>,
+    /// Prebuilt index written by `processor build-index`, rebuilt on start
+    /// when the geonames files or the filters below change
+    pub index_path: Option<String>,
+    /// Resolutions (0-15) of the H3 cells written for each point
+    pub h3_resolutions: Vec<u8>,
+    /// Optional geonames countryInfo.txt, for country names, ISO3 codes and
+    /// continents
+    pub country_info_path: Option<String>,
+    /// Optional geonames admin1CodesASCII.txt, for region names
+    pub admin1_codes_path: Option<String>,
+    /// Optional geonames admin2Codes.txt, for admin2 names
+    pub admin2_codes_path: Option<String>,
+    /// Optional boundary polygons, which take precedence over geonames
+    /// places for country and region
+    #[serde(default)]
+    pub boundaries: Vec<BoundaryLayerConfig>,
+    /// Geonames feature classes to index (e.g. `P` for populated places);
+    /// empty indexes every class
+    #[serde(default)]
+    pub feature_classes: Vec<String>,
+    /// Feature codes to index even outside `feature_classes`
+    #[serde(default)]
+    pub include_feature_codes: Vec<String>,
+    /// Feature codes never to index, e.g. `PPLX` sections of populated places
+    #[serde(default)]
+    pub exclude_feature_codes: Vec<String>,
+    /// Places with a smaller population are not indexed
+    pub min_population: u64,
+    /// How strongly larger places are preferred over closer ones when picking
+    /// the reported place; 0, the default, picks the closest
+    pub population_weight: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BoundaryLayerConfig {
+    /// GeoJSON file, or Shapefile if it ends in `.shp`
+    pub path: String,
+    pub kind: BoundaryKind,
+    /// Feature property holding the ISO2 country code
+    pub country_property: Option<String>,
+    /// Feature property holding the admin1 code, either as in geonames
+    /// (`ENG`) or prefixed with the country (`GB.ENG`)
+    pub region_property: Option<String>,
+    /// Feature property holding the name of the country, region or marine
+    /// region
+    pub name_property: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundaryKind {
+    Country,
+    Admin1,
+    /// Marine regions such as exclusive economic zones, assigning offshore
+    /// points to a country
+    Marine,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GeofenceConfig {
+    /// H3 resolution of the cells zones are indexed by. Finer cells need
+    /// fewer exact polygon checks but more memory.
+    pub resolution: u8,
+    #[serde(default)]
+    pub files: Vec<GeofenceFileConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GeofenceFileConfig {
+    /// GeoJSON file of zone polygons
+    pub path: String,
+    /// Layer the zones belong to, tagged as `zone_<layer>` with the id of the
+    /// zone containing the point and `zone_<layer>_name` with its name. Files
+    /// may share a layer; zones within one should not overlap.
+    pub layer: String,
+    /// Feature property holding the zone id
+    pub id_property: String,
+    /// Feature property holding the zone name
+    pub name_property: Option<String>,
+}
+
+impl GeocoderConfig {
+    /// Every configured geonames dump, in the order they are indexed
+    pub fn source_files(&self) -> Vec<&str> {
+        self.geonames_file_path
+            .iter()
+            .chain(&self.geonames_files)
+            .map(String::as_str)
+            .collect()
+    }
 }
 
 impl ProcessorConfig {
-    pub fn load() -> Result<Self, ConfigError> {
-        let config = Config::builder()
+    /// Load configuration from built-in defaults, then the optional YAML/TOML
+    /// file, then `PROCESSOR_*` environment variables (`__` separates nested
+    /// keys, e.g. `PROCESSOR_KAFKA__BOOTSTRAP_SERVERS`). Keys that do not
+    /// match any setting are rejected.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        Self::load_with_environment(path, std::env::vars().collect())
+    }
+
+    /// `load`, taking the environment variables from `environment`
+    fn load_with_environment(
+        path: Option<&Path>,
+        mut environment: HashMap<String, String>,
+    ) -> Result<Self, ConfigError> {
+        let mut builder = Config::builder()
             // Default values
             .set_default("kafka.bootstrap_servers", "localhost:9092")?
             .set_default("kafka.group_id", "climate-processor")?
             .set_default("kafka.topic", "weather.raw")?
             .set_default("kafka.auto_offset_reset", "earliest")?
+            .set_default("kafka.commit_interval_ms", 5000)?
             .set_default("processing.enable_validation", true)?
             .set_default("processing.enable_enrichment", true)?
             .set_default("processing.enable_aggregation", true)?
             .set_default("processing.batch_size", 100)?
-            .set_default("processing.validation_rules.temperature_min", -100.0)?
-            .set_default("processing.validation_rules.temperature_max", 100.0)?
-            .set_default("processing.validation_rules.humidity_min", 0.0)?
-            .set_default("processing.validation_rules.humidity_max", 100.0)?
+            .set_default("processing.batch_linger_ms", 1000)?
+            .set_default("processing.aggregation.windows", vec!["1h", "1d"])?
+            .set_default("processing.aggregation.h3_resolution", 5)?
+            .set_default(
+                "processing.aggregation.percentiles",
+                vec![50.0, 90.0, 95.0, 99.0],
+            )?
+            .set_default("processing.aggregation.allowed_lateness", "10m")?
+            .set_default("processing.aggregation.idle_partition_timeout", "1m")?
+            .set_default("processing.aggregation.max_clock_skew", "5m")?
+            .set_default(
+                "processing.aggregation.checkpoint_path",
+                "aggregation_state.json",
+            )?
             .set_default("influxdb.host", "localhost")?
             .set_default("influxdb.port", 8086)?
             .set_default("influxdb.org", "emma")?
             .set_default("influxdb.bucket", "climate")?
             .set_default("influxdb.token", "emma-token")?
             .set_default("geocoder.geonames_file_path", "allCountries.txt")?
-            // Override with environment variables
-            .add_source(Environment::with_prefix("PROCESSOR").separator("_"))
+            .set_default("geocoder.index_path", "geonames_index.bin")?
+            .set_default("geocoder.h3_resolutions", (0..=8).collect::<Vec<i64>>())?
+            .set_default("geocoder.feature_classes", vec!["P"])?
+            .set_default(
+                "geocoder.exclude_feature_codes",
+                vec!["PPLX", "PPLH", "PPLQ", "PPLW"],
+            )?
+            .set_default("geocoder.min_population", 0)?
+            .set_default("geocoder.population_weight", 0.0)?
+            .set_default("geofences.resolution", 9)?
+            .set_default("units.unknown_units", "accept")?
+            .set_default("spool.directory", "spool")?
+            .set_default("spool.max_bytes", 1024 * 1024 * 1024)?
+            .set_default("spool.full_policy", "block")?
+            .set_default("spool.initial_backoff_ms", 500)?
+            .set_default("spool.max_backoff_ms", 60_000)?
+            .set_default("metrics.report_interval_ms", 60_000)?;
+
+        if let Some(path) = path {
+            builder = builder.add_source(File::from(path));
+        }
+
+        // Override with environment variables, except the one naming the file
+        environment.remove(CONFIG_FILE_ENV);
+        let config = builder
+            .add_source(
+                Environment::with_prefix("PROCESSOR")
+                    .prefix_separator("_")
+                    .separator("__")
+                    .source(Some(environment.clone())),
+            )
             .build()?;
 
-        config.try_deserialize()
+        let mut unknown_keys = Vec::new();
+        let processor_config: Self =
+            serde_ignored::deserialize(config.clone(), |key| unknown_keys.push(key.to_string()))?;
+
+        if !unknown_keys.is_empty() {
+            let keys: Vec<String> = unknown_keys
+                .iter()
+                .map(|key| describe_key_origin(key, path, &environment))
+                .collect();
+            return Err(ConfigError::Message(format!(
+                "Unknown configuration key(s): {}",
+                keys.join(", ")
+            )));
+        }
+
+        Ok(processor_config)
+    }
+}
+
+/// Name the key together with where it was set, so it can be found and fixed
+fn describe_key_origin(
+    key: &str,
+    path: Option<&Path>,
+    environment: &HashMap<String, String>,
+) -> String {
+    let env_var = format!("PROCESSOR_{}", key.replace('.', "__").to_uppercase());
+    if environment.contains_key(&env_var) {
+        format!("`{key}` (from environment variable {env_var})")
+    } else if let Some(path) = path {
+        format!("`{key}` (in {})", path.display())
+    } else {
+        format!("`{key}`")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Write a config file that is removed again on drop
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("emma-{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn environment(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn load_error(path: &Path, variables: &[(&str, &str)]) -> String {
+        match ProcessorConfig::load_with_environment(Some(path), environment(variables)) {
+            Ok(_) => panic!("loaded with unknown keys"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_file_which_overrides_defaults() {
+        let file = TempFile::new(
+            "layers.yaml",
+            "kafka:\n  topic: \"data.raw\"\n  bootstrap_servers: \"kafka:9092\"\nprocessing:\n  batch_size: 50\n",
+        );
+        let config = ProcessorConfig::load_with_environment(
+            Some(&file.0),
+            environment(&[
+                ("PROCESSOR_KAFKA__BOOTSTRAP_SERVERS", "broker-1:9092"),
+                ("PROCESSOR_PROCESSING__BATCH_LINGER_MS", "250"),
+                // Names the file rather than setting a `config` key
+                ("PROCESSOR_CONFIG", "layers.yaml"),
+                ("OTHER_SETTING", "ignored"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.kafka.topic, "data.raw");
+        assert_eq!(config.kafka.bootstrap_servers, "broker-1:9092");
+        assert_eq!(config.processing.batch_size, 50);
+        assert_eq!(config.processing.batch_linger_ms, 250);
+        assert_eq!(config.kafka.group_id, "climate-processor");
+        assert_eq!(config.geocoder.population_weight, 0.0);
+    }
+
+    #[test]
+    fn toml_files_are_read_too() {
+        let file = TempFile::new(
+            "config.toml",
+            "[kafka]\ntopic = \"data.toml\"\n\n[spool]\nfull_policy = \"drop_oldest\"\n",
+        );
+        let config = ProcessorConfig::load_with_environment(Some(&file.0), HashMap::new()).unwrap();
+        assert_eq!(config.kafka.topic, "data.toml");
+        assert_eq!(config.spool.full_policy, SpoolFullPolicy::DropOldest);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_where_they_were_set() {
+        let file = TempFile::new("unknown.yaml", "kafka:\n  topci: \"data.raw\"\n");
+        let error = load_error(&file.0, &[]);
+        assert!(
+            error.contains(&format!("`kafka.topci` (in {})", file.0.display())),
+            "{error}"
+        );
+
+        let file = TempFile::new("known.yaml", "kafka:\n  topic: \"data.raw\"\n");
+        let error = load_error(&file.0, &[("PROCESSOR_INFLUXDB__TOKN", "secret")]);
+        assert!(
+            error.contains("`influxdb.tokn` (from environment variable PROCESSOR_INFLUXDB__TOKN)"),
+            "{error}"
+        );
     }
 }
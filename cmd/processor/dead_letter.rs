@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Result};
+use rdkafka::message::{Header, Headers, OwnedHeaders, OwnedMessage};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{ClientConfig, Message};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::KafkaConfig;
+use crate::validation::ValidationReport;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pipeline stage at which a message was given up on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureStage {
+    Decode,
+    Validation,
+    Processing,
+    Write,
+}
+
+impl FailureStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureStage::Decode => "decode",
+            FailureStage::Validation => "validation",
+            FailureStage::Processing => "processing",
+            FailureStage::Write => "write",
+        }
+    }
+}
+
+/// Republishes messages the pipeline could not handle to a dead-letter topic,
+/// keeping the original key, payload and headers so they can be replayed.
+pub struct DeadLetterProducer {
+    producer: FutureProducer,
+    topic: String,
+}
+
+impl DeadLetterProducer {
+    /// Returns `None` when no dead-letter topic is configured
+    pub fn new(config: &KafkaConfig) -> Result<Option<Self>> {
+        let topic = match config.dead_letter_topic.as_deref() {
+            Some(topic) if !topic.is_empty() => topic.to_string(),
+            _ => return Ok(None),
+        };
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &config.bootstrap_servers)
+            .set("message.timeout.ms", SEND_TIMEOUT.as_millis().to_string())
+            .create()
+            .map_err(|e| anyhow!("Failed to create dead-letter producer: {}", e))?;
+
+        info!(
+            "🪦 Dead-lettering failed messages to Kafka topic: {}",
+            topic
+        );
+
+        Ok(Some(DeadLetterProducer { producer, topic }))
+    }
+
+    pub async fn send(
+        &self,
+        message: &OwnedMessage,
+        stage: FailureStage,
+        reason: &str,
+        validation: Option<&ValidationReport>,
+    ) -> Result<()> {
+        let headers = dead_letter_headers(message, stage, reason, validation)?;
+        let partition = message.partition();
+        let offset = message.offset();
+
+        let mut record: FutureRecord<'_, [u8], [u8]> =
+            FutureRecord::to(&self.topic).headers(headers);
+        if let Some(key) = message.key() {
+            record = record.key(key);
+        }
+        if let Some(payload) = message.payload() {
+            record = record.payload(payload);
+        }
+
+        self.producer
+            .send(record, SEND_TIMEOUT)
+            .await
+            .map_err(|(e, _)| {
+                anyhow!(
+                    "Failed to dead-letter message {}[{}]@{}: {}",
+                    message.topic(),
+                    partition,
+                    offset,
+                    e
+                )
+            })?;
+
+        warn!(
+            "🪦 Dead-lettered message {}[{}]@{} at {} stage: {}",
+            message.topic(),
+            partition,
+            offset,
+            stage.as_str(),
+            reason
+        );
+
+        Ok(())
+    }
+}
+
+/// The original headers plus `dlq_*` headers saying where the message came
+/// from and why it was dead-lettered
+fn dead_letter_headers(
+    message: &OwnedMessage,
+    stage: FailureStage,
+    reason: &str,
+    validation: Option<&ValidationReport>,
+) -> Result<OwnedHeaders> {
+    let mut headers = OwnedHeaders::new();
+    if let Some(original) = message.headers() {
+        for header in original.iter() {
+            headers = headers.insert(header);
+        }
+    }
+
+    let partition = message.partition().to_string();
+    let offset = message.offset().to_string();
+    let source_timestamp = message
+        .timestamp()
+        .to_millis()
+        .map(|ms| ms.to_string())
+        .unwrap_or_default();
+    let failed_at = chrono::Utc::now().to_rfc3339();
+    let validation_failures = validation
+        .map(|report| serde_json::to_string(&report.failures))
+        .transpose()?;
+
+    for (key, value) in [
+        ("dlq_failure_stage", stage.as_str()),
+        ("dlq_failure_reason", reason),
+        ("dlq_source_topic", message.topic()),
+        ("dlq_source_partition", partition.as_str()),
+        ("dlq_source_offset", offset.as_str()),
+        ("dlq_source_timestamp", source_timestamp.as_str()),
+        ("dlq_failed_at", failed_at.as_str()),
+    ] {
+        headers = headers.insert(Header {
+            key,
+            value: Some(value),
+        });
+    }
+    if let Some(validation_failures) = &validation_failures {
+        headers = headers.insert(Header {
+            key: "dlq_validation_failures",
+            value: Some(validation_failures.as_str()),
+        });
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ValidationFailure;
+    use rdkafka::Timestamp;
+    use std::collections::HashMap;
+
+    fn header_map(headers: &OwnedHeaders) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|header| {
+                let value = header
+                    .value
+                    .map(String::from_utf8_lossy)
+                    .unwrap_or_default();
+                (header.key.to_string(), value.into_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn headers_keep_the_original_ones_and_say_why() {
+        let original = OwnedHeaders::new().insert(Header {
+            key: "trace_id",
+            value: Some("abc123"),
+        });
+        let message = OwnedMessage::new(
+            Some(b"payload".to_vec()),
+            Some(b"sensor-1".to_vec()),
+            "data.raw".to_string(),
+            Timestamp::CreateTime(1_700_000_000_000),
+            3,
+            42,
+            Some(original),
+        );
+        let mut report = ValidationReport::default();
+        report.push(ValidationFailure::new(
+            "environmental.temperature",
+            "max",
+            "value",
+        ));
+
+        let headers = dead_letter_headers(
+            &message,
+            FailureStage::Validation,
+            "out of range",
+            Some(&report),
+        )
+        .unwrap();
+        let headers = header_map(&headers);
+
+        assert_eq!(headers["trace_id"], "abc123");
+        assert_eq!(headers["dlq_failure_stage"], "validation");
+        assert_eq!(headers["dlq_failure_reason"], "out of range");
+        assert_eq!(headers["dlq_source_topic"], "data.raw");
+        assert_eq!(headers["dlq_source_partition"], "3");
+        assert_eq!(headers["dlq_source_offset"], "42");
+        assert_eq!(headers["dlq_source_timestamp"], "1700000000000");
+        assert!(chrono::DateTime::parse_from_rfc3339(&headers["dlq_failed_at"]).is_ok());
+
+        let failures: serde_json::Value =
+            serde_json::from_str(&headers["dlq_validation_failures"]).unwrap();
+        assert_eq!(failures[0]["rule_id"], "environmental.temperature");
+        assert_eq!(failures[0]["check"], "max");
+    }
+
+    #[test]
+    fn headers_leave_out_validation_failures_for_other_stages() {
+        let message = OwnedMessage::new(
+            None,
+            None,
+            "data.raw".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            7,
+            None,
+        );
+
+        let headers =
+            dead_letter_headers(&message, FailureStage::Decode, "invalid protobuf", None).unwrap();
+        let headers = header_map(&headers);
+
+        assert_eq!(headers["dlq_failure_stage"], "decode");
+        assert_eq!(headers["dlq_source_timestamp"], "");
+        assert!(!headers.contains_key("dlq_validation_failures"));
+    }
+}
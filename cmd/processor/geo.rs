@@ -1,121 +1,665 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
 use h3o::{CellIndex, LatLng, Resolution};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::BufRead;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
 
+use crate::boundaries::Boundaries;
+use crate::config::GeocoderConfig;
+use crate::geo_index::{GeoIndexFile, IndexKey};
+use crate::geo_names::{AdminNames, CountryInfo};
+
+/// H3 resolution of the cells places are bucketed by for nearest-place search
+const PLACE_RESOLUTION: Resolution = Resolution::Seven;
+/// Rings of neighbouring cells searched for the nearest place, about 20 km
+const MAX_SEARCH_RINGS: u32 = 10;
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+/// Geonames rows parsed in parallel at a time
+const BATCH_ROWS: usize = 65_536;
+/// Rows read between progress reports while building the index
+const PROGRESS_ROWS: usize = 1_000_000;
+
+/// A geonames place, with its strings as ids into `PlaceIndex::strings`
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RegionInfo {
-    pub country: String,
-    pub region: String,
-    pub timezone: String,
-    pub nearest_place: String,
+struct Place {
+    name: u32,
+    country: u32,
+    region: u32,
+    admin2: u32,
+    timezone: u32,
+    lat: f32,
+    lng: f32,
+    population: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocationResult {
-    pub country: String,
-    pub region: String,
-    pub timezone: String,
-    pub nearest_place: String,
-    pub h3_cells: [u64; 9], // H3 cell IDs for resolutions 0-8
-    pub resolution_used: u8,
+    /// ISO2 country code, from the boundary layers or the reported place
+    pub country: Option<Arc<str>>,
+    /// Admin1 code, empty where neither gives one
+    pub region: Arc<str>,
+    /// Admin2 code, empty where geonames has none
+    pub admin2: Arc<str>,
+    /// Timezone of the reported place, when a place was found nearby
+    pub timezone: Option<Arc<str>>,
+    pub nearest_place: Option<Arc<str>>,
+    /// Great-circle distance to the reported place
+    pub distance_km: Option<f64>,
+    /// Initial bearing from the point to the reported place, clockwise from north
+    pub bearing_deg: Option<f64>,
+    /// Resolution and H3 cell ID for each configured `h3_resolutions`
+    pub h3_cells: Vec<(u8, u64)>,
+    /// Resolution at which the region lookup found a place
+    pub resolution_used: Option<u8>,
+    /// Names for the codes above, when the geonames name files are configured
+    pub country_name: Option<Arc<str>>,
+    pub country_iso3: Option<Arc<str>>,
+    pub continent: Option<Arc<str>>,
+    pub region_name: Option<Arc<str>>,
+    pub admin2_name: Option<Arc<str>>,
+    /// Marine region containing the point, when a marine boundary layer is
+    /// configured
+    pub marine_region: Option<Arc<str>>,
 }
 
-pub struct H3Geocoder {
-    region_maps: [HashMap<u64, RegionInfo>; 9],
+/// Places and the cells they are indexed by, as saved by `build-index`
+#[derive(Serialize, Deserialize)]
+pub struct PlaceIndex {
+    /// Every distinct name, country, region and timezone, stored once
+    strings: Vec<Arc<str>>,
+    places: Vec<Place>,
+    /// Most populous place per cell, by index into `places`, for resolutions 0-8
+    region_maps: [CellMap; 9],
+    /// All places per cell at `PLACE_RESOLUTION`
+    place_cells: CellMap,
 }
 
-impl H3Geocoder {
-    pub fn from_geonames_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+/// Places by cell id, sorted by cell for binary search. Much smaller than a
+/// `HashMap` of the same entries.
+#[derive(Default, Serialize, Deserialize)]
+struct CellMap(Vec<(u64, u32)>);
+
+impl CellMap {
+    fn places(&self, cell: u64) -> impl Iterator<Item = u32> + '_ {
+        let start = self.0.partition_point(|&(c, _)| c < cell);
+        self.0[start..]
+            .iter()
+            .take_while(move |&&(c, _)| c == cell)
+            .map(|&(_, place)| place)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0.capacity() * std::mem::size_of::<(u64, u32)>()
+    }
+}
+
+impl FromIterator<(u64, u32)> for CellMap {
+    fn from_iter<I: IntoIterator<Item = (u64, u32)>>(entries: I) -> Self {
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_unstable();
+        entries.shrink_to_fit();
+        CellMap(entries)
+    }
+}
+
+/// Hands out one id per distinct string while the index is built
+#[derive(Default)]
+struct Interner {
+    strings: Vec<Arc<str>>,
+    ids: HashMap<Arc<str>, u32>,
+}
+
+impl Interner {
+    fn intern(&mut self, string: &str) -> Result<u32> {
+        if let Some(&id) = self.ids.get(string) {
+            return Ok(id);
+        }
+        let id = u32::try_from(self.strings.len())?;
+        let string: Arc<str> = Arc::from(string);
+        self.strings.push(Arc::clone(&string));
+        self.ids.insert(string, id);
+        Ok(id)
+    }
+}
+
+/// Outcome of parsing one geonames row
+enum Row<'a> {
+    Place {
+        /// Name, country, admin1, admin2 and timezone
+        strings: [&'a str; 5],
+        lat: f64,
+        lng: f64,
+        population: u64,
+        /// Cells for resolutions 0-8
+        region_cells: [u64; 9],
+        place_cell: u64,
+    },
+    /// Left out by the feature class, feature code or population filters
+    Filtered,
+    Malformed,
+}
+
+impl<'a> Row<'a> {
+    fn parse(config: &GeocoderConfig, line: &'a str) -> Self {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 18 {
+            return Row::Malformed;
+        }
+        let (Ok(lat), Ok(lng)) = (fields[4].parse::<f64>(), fields[5].parse::<f64>()) else {
+            return Row::Malformed;
+        };
+        let Ok(coord) = LatLng::new(lat, lng) else {
+            return Row::Malformed;
+        };
+
+        // Feature class, feature code and population
+        let population = fields[14].parse().unwrap_or(0);
+        if population < config.min_population || !is_indexed(config, fields[6], fields[7]) {
+            return Row::Filtered;
+        }
+
+        let mut region_cells = [0; 9];
+        for (resolution, cell) in
+            Resolution::range(Resolution::Zero, Resolution::Eight).zip(&mut region_cells)
+        {
+            *cell = coord.to_cell(resolution).into();
+        }
+        Row::Place {
+            strings: [fields[1], fields[8], fields[10], fields[11], fields[17]],
+            lat,
+            lng,
+            population,
+            region_cells,
+            place_cell: coord.to_cell(PLACE_RESOLUTION).into(),
+        }
+    }
+}
+
+/// Accumulates a `PlaceIndex` from geonames rows. Rows are parsed in
+/// parallel but added in file order, so the index is the same whatever the
+/// number of threads.
+struct IndexBuilder<'a> {
+    config: &'a GeocoderConfig,
+    strings: Interner,
+    places: Vec<Place>,
+    region_maps: [HashMap<u64, u32>; 9],
+    place_cells: Vec<(u64, u32)>,
+    rows: usize,
+    filtered: usize,
+    malformed: usize,
+}
+
+impl<'a> IndexBuilder<'a> {
+    fn new(config: &'a GeocoderConfig) -> Self {
+        IndexBuilder {
+            config,
+            strings: Interner::default(),
+            places: Vec::new(),
+            region_maps: Default::default(),
+            place_cells: Vec::new(),
+            rows: 0,
+            filtered: 0,
+            malformed: 0,
+        }
+    }
+
+    /// Add the rows of a plain, gzipped or zipped geonames dump. Every `.txt`
+    /// file in a zip is read, except the readme geonames includes; a zip
+    /// without one is an error.
+    fn add_file(&mut self, path: &str) -> Result<()> {
         let file = std::fs::File::open(path)?;
-        let reader = std::io::BufReader::new(file);
-
-        let mut region_maps: [HashMap<u64, RegionInfo>; 9] = Default::default();
-
-        println!("Building H3 spatial index for resolutions 0-8...");
-
-        for (line_num, line) in reader.lines().enumerate() {
-            let line = line?;
-            let fields: Vec<&str> = line.split('\t').collect();
-
-            if fields.len() >= 18 {
-                if let (Ok(lat), Ok(lng)) = (fields[4].parse::<f64>(), fields[5].parse::<f64>()) {
-                    // Create LatLng coordinate (MUST ensure valid)
-                    if let Ok(coord) = LatLng::new(lat, lng) {
-                        let region_info = RegionInfo {
-                            country: fields[8].to_string(),
-                            region: fields[10].to_string(),
-                            timezone: fields[17].to_string(),
-                            nearest_place: fields[1].to_string(),
-                        };
-
-                        // Build cells for all resolutions 0-8
-                        for res in 0..=8u8 {
-                            let resolution = Resolution::try_from(res)?;
-                            let cell = coord.to_cell(resolution);
-                            let cell_id: u64 = cell.into();
-
-                            // Only insert if we don't have this cell yet
-                            region_maps[res as usize]
-                                .entry(cell_id)
-                                .or_insert(region_info.clone());
-                        }
-                    }
-                }
+        if path.ends_with(".gz") {
+            return self.add_rows(BufReader::new(MultiGzDecoder::new(file)));
+        }
+        if !path.ends_with(".zip") {
+            return self.add_rows(BufReader::new(file));
+        }
+
+        let mut archive = zip::ZipArchive::new(file)?;
+        let mut found = false;
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            let name = entry.name().to_string();
+            if name.ends_with(".txt") && !name.eq_ignore_ascii_case("readme.txt") {
+                info!("🗺️ Reading {} from {}", name, path);
+                self.add_rows(BufReader::new(entry))?;
+                found = true;
+            }
+        }
+        if !found {
+            bail!("No geonames .txt file in {}", path);
+        }
+        Ok(())
+    }
+
+    fn add_rows(&mut self, reader: impl BufRead) -> Result<()> {
+        let mut lines = reader.lines();
+        loop {
+            let batch = lines
+                .by_ref()
+                .take(BATCH_ROWS)
+                .collect::<Result<Vec<String>, _>>()?;
+            if batch.is_empty() {
+                return Ok(());
             }
 
-            if line_num % 100_000 == 0 {
-                println!("Processed {line_num} locations...");
+            let rows: Vec<Row> = batch
+                .par_iter()
+                .map(|line| Row::parse(self.config, line))
+                .collect();
+            for row in rows {
+                self.add_row(row)?;
+            }
+
+            let rows_before = self.rows;
+            self.rows += batch.len();
+            if self.rows / PROGRESS_ROWS > rows_before / PROGRESS_ROWS {
+                info!(
+                    "🗺️ Read {} geonames rows, {} places indexed",
+                    self.rows,
+                    self.places.len()
+                );
             }
         }
+    }
 
-        // Print stats
-        for (res, map) in region_maps.iter().enumerate() {
-            println!("Resolution {}: {} unique cells", res, map.len());
+    fn add_row(&mut self, row: Row) -> Result<()> {
+        let Row::Place {
+            strings: [name, country, region, admin2, timezone],
+            lat,
+            lng,
+            population,
+            region_cells,
+            place_cell,
+        } = row
+        else {
+            match row {
+                Row::Filtered => self.filtered += 1,
+                _ => self.malformed += 1,
+            }
+            return Ok(());
+        };
+
+        let place = u32::try_from(self.places.len())?;
+        self.places.push(Place {
+            name: self.strings.intern(name)?,
+            country: self.strings.intern(country)?,
+            region: self.strings.intern(region)?,
+            admin2: self.strings.intern(admin2)?,
+            timezone: self.strings.intern(timezone)?,
+            lat: lat as f32,
+            lng: lng as f32,
+            population,
+        });
+        self.place_cells.push((place_cell, place));
+
+        for (map, cell) in self.region_maps.iter_mut().zip(region_cells) {
+            // Keep the most populous place, the first one on ties
+            map.entry(cell)
+                .and_modify(|best| {
+                    if population > self.places[*best as usize].population {
+                        *best = place;
+                    }
+                })
+                .or_insert(place);
         }
+        Ok(())
+    }
 
-        Ok(Self { region_maps })
+    fn finish(self) -> PlaceIndex {
+        info!(
+            "🗺️ Indexed {} places from {} geonames rows ({} filtered out, {} malformed)",
+            self.places.len(),
+            self.rows,
+            self.filtered,
+            self.malformed
+        );
+        let cell_counts: Vec<String> = self
+            .region_maps
+            .iter()
+            .enumerate()
+            .map(|(resolution, map)| format!("{}: {}", resolution, map.len()))
+            .collect();
+        info!("🗺️ Cells per resolution: {}", cell_counts.join(", "));
+
+        let mut places = self.places;
+        places.shrink_to_fit();
+        let mut strings = self.strings.strings;
+        strings.shrink_to_fit();
+        PlaceIndex {
+            strings,
+            places,
+            region_maps: self.region_maps.map(|map| map.into_iter().collect()),
+            place_cells: self.place_cells.into_iter().collect(),
+        }
     }
+}
 
-    /// Get everything at once: country, region, timezone, and all H3 cell IDs
-    pub fn get_complete_location_info(&self, lat: f64, lng: f64) -> Option<LocationResult> {
-        let coord = LatLng::new(lat, lng).ok()?;
+impl PlaceIndex {
+    /// Build the index from every configured geonames file, merged in order
+    pub fn from_geonames_files(config: &GeocoderConfig) -> Result<Self> {
+        let paths = config.source_files();
+        if paths.is_empty() {
+            bail!("No geonames files configured");
+        }
+
+        let mut builder = IndexBuilder::new(config);
+        for path in paths {
+            info!("🗺️ Building H3 spatial index from {}", path);
+            builder
+                .add_file(path)
+                .with_context(|| format!("Failed to read {}", path))?;
+        }
+        Ok(builder.finish())
+    }
+
+    pub fn place_count(&self) -> usize {
+        self.places.len()
+    }
+
+    pub fn string_count(&self) -> usize {
+        self.strings.len()
+    }
 
-        // Compute H3 cells for all resolutions
-        let mut h3_cells = [0u64; 9];
+    /// Approximate heap memory held by the index, in bytes
+    pub fn memory_usage(&self) -> usize {
+        // Each `Arc<str>` allocation carries two reference counts
+        let strings: usize = self
+            .strings
+            .iter()
+            .map(|string| string.len() + 2 * std::mem::size_of::<usize>())
+            .sum();
+        strings
+            + self.strings.capacity() * std::mem::size_of::<Arc<str>>()
+            + self.places.capacity() * std::mem::size_of::<Place>()
+            + self
+                .region_maps
+                .iter()
+                .map(CellMap::memory_usage)
+                .sum::<usize>()
+            + self.place_cells.memory_usage()
+    }
 
-        for res in 0..=8u8 {
-            if let Ok(resolution) = Resolution::try_from(res) {
-                let cell = coord.to_cell(resolution);
-                h3_cells[res as usize] = cell.into();
+    fn string(&self, id: u32) -> Arc<str> {
+        Arc::clone(&self.strings[id as usize])
+    }
+}
+
+/// `AdminNames` keyed by the string ids of the codes in a `PlaceIndex`, so
+/// lookups need no allocation. Codes no place uses are left out.
+#[derive(Default)]
+struct NameIds {
+    /// By country code, as boundary layers may assign countries too
+    countries: HashMap<String, CountryInfo>,
+    admin1: HashMap<[u32; 2], Arc<str>>,
+    admin2: HashMap<[u32; 3], Arc<str>>,
+}
+
+impl NameIds {
+    fn new(index: &PlaceIndex, names: AdminNames) -> Self {
+        let ids: HashMap<&str, u32> = (0u32..)
+            .zip(&index.strings)
+            .map(|(id, string)| (&**string, id))
+            .collect();
+        let id = |code: &str| ids.get(code).copied();
+
+        NameIds {
+            countries: names.countries.into_iter().collect(),
+            admin1: names
+                .admin1
+                .into_iter()
+                .filter_map(|([country, admin1], name)| Some(([id(&country)?, id(&admin1)?], name)))
+                .collect(),
+            admin2: names
+                .admin2
+                .into_iter()
+                .filter_map(|([country, admin1, admin2], name)| {
+                    Some(([id(&country)?, id(&admin1)?, id(&admin2)?], name))
+                })
+                .collect(),
+        }
+    }
+}
+
+pub struct H3Geocoder {
+    index: PlaceIndex,
+    names: NameIds,
+    boundaries: Boundaries,
+    /// Resolutions of the cells reported for each point, independent of the
+    /// resolutions places are indexed at
+    resolutions: Vec<Resolution>,
+    population_weight: f64,
+}
+
+impl H3Geocoder {
+    pub fn index(&self) -> &PlaceIndex {
+        &self.index
+    }
+
+    pub fn new(
+        index: PlaceIndex,
+        names: AdminNames,
+        boundaries: Boundaries,
+        resolutions: Vec<Resolution>,
+        config: &GeocoderConfig,
+    ) -> Self {
+        H3Geocoder {
+            names: NameIds::new(&index, names),
+            index,
+            boundaries,
+            resolutions,
+            population_weight: config.population_weight,
+        }
+    }
+
+    /// Use the index saved at `index_path` if it was built from the current
+    /// geonames file with the current filters. Otherwise the index is built
+    /// from the geonames file and saved there for the next start. Without the
+    /// geonames files, the saved index is used as long as it was built with
+    /// the current filters.
+    pub fn load(config: &GeocoderConfig) -> Result<Self> {
+        let mut resolutions = config
+            .h3_resolutions
+            .iter()
+            .map(|&resolution| Resolution::try_from(resolution))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid geocoder.h3_resolutions")?;
+        resolutions.sort_unstable();
+        resolutions.dedup();
+
+        let names = AdminNames::load(config)?;
+        if !names.is_empty() {
+            info!(
+                "🗺️ Loaded names for {} countries, {} admin1 and {} admin2 regions",
+                names.countries.len(),
+                names.admin1.len(),
+                names.admin2.len()
+            );
+        }
+
+        let boundaries = Boundaries::load(&config.boundaries)?;
+        if !boundaries.is_empty() {
+            info!("🗺️ Loaded {} boundary polygons", boundaries.len());
+        }
+
+        let Some(index_path) = &config.index_path else {
+            let index = PlaceIndex::from_geonames_files(config)?;
+            return Ok(Self::new(index, names, boundaries, resolutions, config));
+        };
+
+        let index_file = GeoIndexFile::new(index_path);
+        let missing: Vec<&str> = config
+            .source_files()
+            .into_iter()
+            .filter(|path| !Path::new(path).exists())
+            .collect();
+        if !missing.is_empty() {
+            // Nothing to rebuild from, so the saved index is the only option
+            let index = index_file.load_without_sources(config)?.with_context(|| {
+                format!(
+                    "Geocoder index {} is missing or was built with other filters, and {} cannot be found to rebuild it",
+                    index_path,
+                    missing.join(", ")
+                )
+            })?;
+            warn!(
+                "🗺️ {} not found, using geocoder index {} without checking it is up to date",
+                missing.join(", "),
+                index_path
+            );
+            return Ok(Self::new(index, names, boundaries, resolutions, config));
+        }
+
+        let key = IndexKey::new(config)?;
+        match index_file.load(&key) {
+            Ok(Some(index)) => {
+                info!("🗺️ Loaded geocoder index from {}", index_path);
+                return Ok(Self::new(index, names, boundaries, resolutions, config));
             }
+            Ok(None) => info!(
+                "🗺️ Geocoder index {} is missing or out of date, rebuilding",
+                index_path
+            ),
+            Err(e) => warn!("{:#}, rebuilding", e),
         }
 
+        let index = PlaceIndex::from_geonames_files(config)?;
+        if let Err(e) = index_file.save(&key, &index) {
+            warn!("{:#}", e);
+        }
+        Ok(Self::new(index, names, boundaries, resolutions, config))
+    }
+
+    /// Get everything at once: country, region, timezone, and all H3 cell IDs.
+    /// Where no place is indexed nearby, e.g. far offshore, only what the
+    /// boundary layers give is reported.
+    pub fn get_complete_location_info(&self, lat: f64, lng: f64) -> Option<LocationResult> {
+        let coord = LatLng::new(lat, lng).ok()?;
+        let h3_cells = self.h3_cells(coord);
+
         // Find region info (try highest resolution first)
-        for res in (0..=8usize).rev() {
-            let cell_id = h3_cells[res];
-            if let Some(region_info) = self.region_maps[res].get(&cell_id) {
-                return Some(LocationResult {
-                    country: region_info.country.clone(),
-                    region: region_info.region.clone(),
-                    timezone: region_info.timezone.clone(),
-                    nearest_place: region_info.nearest_place.clone(),
-                    h3_cells,
-                    resolution_used: res as u8,
-                });
+        let region_place = Resolution::range(Resolution::Zero, Resolution::Eight)
+            .rev()
+            .find_map(|resolution| {
+                self.index.region_maps[usize::from(resolution)]
+                    .places(coord.to_cell(resolution).into())
+                    .next()
+                    .map(|place| (u8::from(resolution), place))
+            });
+
+        // The region comes from the best place too, so the two always agree.
+        // Places further away than the search radius fall back to the one
+        // found for the region.
+        let best = region_place.map(|(_, region_place)| {
+            &self.index.places[self.best_place(coord).unwrap_or(region_place) as usize]
+        });
+
+        // Boundary polygons overrule the place's country and region. Its
+        // admin codes are only kept as far as they still agree.
+        let boundary = self.boundaries.locate(lat, lng);
+        let place_country = best.map(|best| self.index.string(best.country));
+        let country = boundary.country.or_else(|| place_country.clone());
+        let same_place = best.filter(|_| country.is_some() && country == place_country);
+        let (region, region_name) = match (boundary.region, same_place) {
+            (Some(region), _) => (region, boundary.region_name),
+            (None, Some(best)) => (
+                self.index.string(best.region),
+                self.names.admin1.get(&[best.country, best.region]).cloned(),
+            ),
+            (None, None) => (Arc::from(""), None),
+        };
+        let (admin2, admin2_name) = match same_place {
+            Some(best) if *region == *self.index.string(best.region) => (
+                self.index.string(best.admin2),
+                self.names
+                    .admin2
+                    .get(&[best.country, best.region, best.admin2])
+                    .cloned(),
+            ),
+            _ => (Arc::from(""), None),
+        };
+        let country_info = country
+            .as_deref()
+            .and_then(|country| self.names.countries.get(country));
+
+        Some(LocationResult {
+            region,
+            admin2,
+            timezone: best.map(|best| self.index.string(best.timezone)),
+            nearest_place: best.map(|best| self.index.string(best.name)),
+            distance_km: best.map(|best| haversine_km(coord, best)),
+            bearing_deg: best.map(|best| bearing_deg(coord, best)),
+            h3_cells,
+            resolution_used: region_place.map(|(resolution, _)| resolution),
+            country_name: country_info
+                .map(|info| Arc::clone(&info.name))
+                .or(boundary.country_name),
+            country_iso3: country_info.map(|info| Arc::clone(&info.iso3)),
+            continent: country_info.map(|info| Arc::clone(&info.continent)),
+            region_name,
+            admin2_name,
+            marine_region: boundary.marine_region,
+            country,
+        })
+    }
+
+    /// Cells at every configured resolution, which every valid coordinate has
+    /// whether or not a place or boundary is found for it
+    fn h3_cells(&self, coord: LatLng) -> Vec<(u8, u64)> {
+        self.resolutions
+            .iter()
+            .map(|&resolution| (u8::from(resolution), u64::from(coord.to_cell(resolution))))
+            .collect()
+    }
+
+    /// Place within the search radius with the lowest great-circle distance,
+    /// discounted by population according to `population_weight`. Without a
+    /// weight the search stops at the first ring that cannot hold a place
+    /// closer than the best one found, so the closest place is exact; with
+    /// one, a larger place further out may still win, so every ring is
+    /// searched.
+    fn best_place(&self, coord: LatLng) -> Option<u32> {
+        let origin = coord.to_cell(PLACE_RESOLUTION);
+        let mut cells: Vec<(CellIndex, u32)> = origin.grid_disk_distances(MAX_SEARCH_RINGS);
+        cells.sort_unstable_by_key(|&(_, ring)| ring);
+
+        // Cell centres in ring k are at least 1.5 k edges from the origin's,
+        // and points lie within an edge of their cell's centre. The margin
+        // covers the distortion of cells across the grid.
+        let edge_km = origin
+            .edges()
+            .map(|edge| edge.length_km())
+            .fold(f64::INFINITY, f64::min);
+        let ring_min_km = |ring: u32| (1.5 * f64::from(ring) - 2.0) * edge_km * 0.9;
+
+        let mut best: Option<(f64, u32)> = None;
+        for (cell, ring) in cells {
+            if self.population_weight <= 0.0
+                && best.is_some_and(|(best_km, _)| ring_min_km(ring) > best_km)
+            {
+                break;
+            }
+            for place in self.index.place_cells.places(u64::from(cell)) {
+                let place_info = &self.index.places[place as usize];
+                let score = haversine_km(coord, place_info)
+                    / (1.0 + self.population_weight * (place_info.population as f64).ln_1p());
+                if best.is_none_or(|(best_score, _)| score < best_score) {
+                    best = Some((score, place));
+                }
             }
         }
 
-        None
+        best.map(|(_, place)| place)
     }
 
     /// Get just the H3 cell ID for a specific resolution
     #[allow(dead_code)]
     pub fn get_cell_id(&self, lat: f64, lng: f64, resolution: u8) -> Option<u64> {
-        if resolution > 8 {
-            return None;
-        }
-
         let coord = LatLng::new(lat, lng).ok()?;
         let res = Resolution::try_from(resolution).ok()?;
         let cell = coord.to_cell(res);
@@ -133,3 +677,331 @@ impl H3Geocoder {
         }
     }
 }
+
+/// Whether a geonames row with this feature class and code is indexed
+fn is_indexed(config: &GeocoderConfig, class: &str, code: &str) -> bool {
+    let listed = |codes: &[String]| codes.iter().any(|c| c == code);
+    if listed(&config.exclude_feature_codes) {
+        return false;
+    }
+    listed(&config.include_feature_codes)
+        || config.feature_classes.is_empty()
+        || config.feature_classes.iter().any(|c| c == class)
+}
+
+fn haversine_km(from: LatLng, to: &Place) -> f64 {
+    let (lat1, lat2) = (from.lat().to_radians(), f64::from(to.lat).to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (f64::from(to.lng) - from.lng()).to_radians();
+
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+fn bearing_deg(from: LatLng, to: &Place) -> f64 {
+    let (lat1, lat2) = (from.lat().to_radians(), f64::from(to.lat).to_radians());
+    let d_lng = (f64::from(to.lng) - from.lng()).to_radians();
+
+    let y = d_lng.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lng.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BoundaryKind, BoundaryLayerConfig};
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn config(path: &str, population_weight: f64) -> GeocoderConfig {
+        serde_json::from_value(serde_json::json!({
+            "geonames_file_path": path,
+            "h3_resolutions": [5, 9],
+            "feature_classes": ["P"],
+            "min_population": 0,
+            "population_weight": population_weight,
+        }))
+        .unwrap()
+    }
+
+    fn temp_path(name: &str, extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("geo-{}-{}.{}", name, std::process::id(), extension))
+    }
+
+    /// Geonames rows for places given as (name, lat, lng, population)
+    fn rows(places: &[(&str, f64, f64, u64)]) -> String {
+        places
+            .iter()
+            .enumerate()
+            .map(|(id, (name, lat, lng, population))| {
+                format!(
+                    "{id}\t{name}\t{name}\t\t{lat}\t{lng}\tP\tPPL\tGB\t\tENG\tJ9\t\t\t{population}\t\t0\tEurope/London\t2024-01-01\n"
+                )
+            })
+            .collect()
+    }
+
+    /// Zip archive holding the given files
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            archive
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            archive.write_all(contents.as_bytes()).unwrap();
+        }
+        archive.finish().unwrap().into_inner()
+    }
+
+    /// Geocoder over places given as (name, lat, lng, population)
+    fn geocoder(
+        name: &str,
+        places: &[(&str, f64, f64, u64)],
+        population_weight: f64,
+    ) -> H3Geocoder {
+        let path = temp_path(name, "txt");
+        std::fs::write(&path, rows(places)).unwrap();
+
+        let config = config(path.to_str().unwrap(), population_weight);
+        let index = PlaceIndex::from_geonames_files(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let resolutions = vec![Resolution::Five, Resolution::Nine];
+        H3Geocoder::new(
+            index,
+            AdminNames::default(),
+            Boundaries::default(),
+            resolutions,
+            &config,
+        )
+    }
+
+    #[test]
+    fn without_population_weight_the_closest_place_is_reported() {
+        // Places scattered around southern England, a few km apart
+        let mut seed = 42u64;
+        let mut random = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let places: Vec<(String, f64, f64, u64)> = (0..400)
+            .map(|i| {
+                (
+                    format!("place{i}"),
+                    51.0 + random() * 0.5,
+                    -1.0 + random() * 0.8,
+                    1000,
+                )
+            })
+            .collect();
+        let places: Vec<(&str, f64, f64, u64)> = places
+            .iter()
+            .map(|(name, lat, lng, population)| (name.as_str(), *lat, *lng, *population))
+            .collect();
+        let geocoder = geocoder("closest", &places, 0.0);
+
+        for _ in 0..500 {
+            let (lat, lng) = (51.1 + random() * 0.3, -0.9 + random() * 0.6);
+            let coord = LatLng::new(lat, lng).unwrap();
+            let closest = geocoder
+                .index
+                .places
+                .iter()
+                .map(|place| haversine_km(coord, place))
+                .fold(f64::INFINITY, f64::min);
+            let best = geocoder.best_place(coord).unwrap();
+            let best_km = haversine_km(coord, &geocoder.index.places[best as usize]);
+            assert!(
+                best_km <= closest + 1e-9,
+                "{best_km} km, closest {closest} km at {lat},{lng}"
+            );
+        }
+    }
+
+    #[test]
+    fn population_weight_prefers_larger_places() {
+        let places = [
+            ("Village", 51.50, -0.10, 0),
+            ("City", 51.52, -0.10, 5_000_000),
+        ];
+        let coord = LatLng::new(51.495, -0.10).unwrap();
+
+        let closest = geocoder("unweighted", &places, 0.0);
+        let place = closest.best_place(coord).unwrap();
+        assert_eq!(
+            &*closest
+                .index
+                .string(closest.index.places[place as usize].name),
+            "Village"
+        );
+
+        let weighted = geocoder("weighted", &places, 1.0);
+        let place = weighted.best_place(coord).unwrap();
+        assert_eq!(
+            &*weighted
+                .index
+                .string(weighted.index.places[place as usize].name),
+            "City"
+        );
+    }
+
+    #[test]
+    fn points_without_places_nearby_get_boundary_fields_only() {
+        let mut geocoder = geocoder("offshore", &[("London", 51.5, -0.12, 8_961_989)], 1.0);
+        let path = std::env::temp_dir().join(format!("geo-eez-{}.geojson", std::process::id()));
+        let eez = serde_json::json!({
+            "type": "FeatureCollection",
+            "features": [{
+                "type": "Feature",
+                "properties": { "iso2": "PN", "name": "Pitcairn EEZ" },
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[[-135.0, -20.0], [-125.0, -20.0], [-125.0, -30.0], [-135.0, -30.0], [-135.0, -20.0]]]
+                }
+            }]
+        });
+        std::fs::write(&path, eez.to_string()).unwrap();
+        let layer = BoundaryLayerConfig {
+            path: path.to_str().unwrap().to_string(),
+            kind: BoundaryKind::Marine,
+            country_property: Some("iso2".to_string()),
+            region_property: None,
+            name_property: Some("name".to_string()),
+        };
+        geocoder.boundaries = Boundaries::load(&[layer]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let location = geocoder.get_complete_location_info(-25.0, -130.0).unwrap();
+        assert_eq!(location.country.as_deref(), Some("PN"));
+        assert_eq!(location.marine_region.as_deref(), Some("Pitcairn EEZ"));
+        assert!(location.nearest_place.is_none());
+        assert!(location.timezone.is_none());
+        assert!(location.resolution_used.is_none());
+        assert_eq!(location.h3_cells.len(), 2);
+
+        let location = geocoder.get_complete_location_info(-45.0, -130.0).unwrap();
+        assert!(location.country.is_none());
+        assert!(location.marine_region.is_none());
+        assert_eq!(location.h3_cells.len(), 2);
+    }
+
+    #[test]
+    fn every_valid_coordinate_gets_its_h3_cells() {
+        let geocoder = geocoder("cells", &[("London", 51.5, -0.12, 8_961_989)], 1.0);
+
+        for (lat, lng) in [(51.5, -0.12), (-45.0, -130.0), (89.9, 0.0)] {
+            let location = geocoder.get_complete_location_info(lat, lng).unwrap();
+            let coord = LatLng::new(lat, lng).unwrap();
+            let expected: Vec<(u8, u64)> = [Resolution::Five, Resolution::Nine]
+                .into_iter()
+                .map(|resolution| (u8::from(resolution), u64::from(coord.to_cell(resolution))))
+                .collect();
+            assert_eq!(location.h3_cells, expected);
+        }
+        assert!(geocoder.get_complete_location_info(f64::NAN, 0.0).is_none());
+    }
+
+    #[test]
+    fn merges_plain_gzipped_and_zipped_dumps() {
+        let plain = temp_path("merge", "txt");
+        std::fs::write(&plain, rows(&[("London", 51.5, -0.12, 8_961_989)])).unwrap();
+
+        let gzipped = temp_path("merge", "txt.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(
+                rows(&[
+                    ("Leeds", 53.8, -1.55, 455_123),
+                    ("York", 53.96, -1.08, 153_717),
+                ])
+                .as_bytes(),
+            )
+            .unwrap();
+        std::fs::write(&gzipped, encoder.finish().unwrap()).unwrap();
+
+        // The readme is skipped; every other text file is read
+        let zipped = temp_path("merge", "zip");
+        let archive = zip(&[
+            ("readme.txt", "not\tgeonames\n"),
+            ("GB.txt", &rows(&[("Bath", 51.38, -2.36, 94_782)])),
+            ("IE.txt", &rows(&[("Cork", 51.9, -8.47, 125_657)])),
+        ]);
+        std::fs::write(&zipped, archive).unwrap();
+
+        let mut config = config(plain.to_str().unwrap(), 0.0);
+        config.geonames_files = vec![
+            gzipped.to_str().unwrap().to_string(),
+            zipped.to_str().unwrap().to_string(),
+        ];
+        let index = PlaceIndex::from_geonames_files(&config).unwrap();
+        for path in [&plain, &gzipped, &zipped] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        assert_eq!(index.place_count(), 5);
+        let names: Vec<&str> = index
+            .places
+            .iter()
+            .map(|place| &*index.strings[place.name as usize])
+            .collect();
+        assert_eq!(names, ["London", "Leeds", "York", "Bath", "Cork"]);
+    }
+
+    #[test]
+    fn zips_without_a_geonames_file_are_an_error() {
+        let zipped = temp_path("no-txt", "zip");
+        std::fs::write(
+            &zipped,
+            zip(&[("readme.txt", "about\n"), ("data.csv", "a,b\n")]),
+        )
+        .unwrap();
+
+        let config = config(zipped.to_str().unwrap(), 0.0);
+        let error = PlaceIndex::from_geonames_files(&config).err().unwrap();
+        std::fs::remove_file(&zipped).unwrap();
+        assert!(
+            format!("{:#}", error).contains("No geonames .txt file"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn parallel_builds_match_a_single_threaded_one() {
+        // More than one batch of rows, with populations that tie
+        let names: Vec<String> = (0..BATCH_ROWS + 5_000)
+            .map(|i| format!("place{i}"))
+            .collect();
+        let places: Vec<(&str, f64, f64, u64)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let (lat, lng) = (
+                    (i % 347) as f64 * 0.37 - 60.0,
+                    (i % 911) as f64 * 0.39 - 170.0,
+                );
+                (name.as_str(), lat, lng, (i % 7) as u64 * 1000)
+            })
+            .collect();
+        let path = temp_path("parallel", "txt");
+        std::fs::write(&path, rows(&places)).unwrap();
+        let config = config(path.to_str().unwrap(), 0.0);
+
+        let build = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let index = pool
+                .install(|| PlaceIndex::from_geonames_files(&config))
+                .unwrap();
+            bincode::serialize(&index).unwrap()
+        };
+        let single = build(1);
+        let parallel = build(8);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(single == parallel, "indexes differ");
+    }
+}
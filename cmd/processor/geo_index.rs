@@ -0,0 +1,290 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::config::GeocoderConfig;
+use crate::geo::PlaceIndex;
+
+const MAGIC: &[u8; 8] = b"EMMAGEO\0";
+/// Bumped whenever the layout of `PlaceIndex` changes
+const FORMAT_VERSION: u32 = 4;
+/// Magic, format version and CRC-32 of the payload
+const HEADER_LEN: usize = MAGIC.len() + 4 + 4;
+
+/// What an index was built from: the geonames files as they were then and
+/// the filters that decided which places went in. An index whose key differs
+/// from the current one is stale.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexKey {
+    sources: Vec<SourceFile>,
+    feature_classes: Vec<String>,
+    include_feature_codes: Vec<String>,
+    exclude_feature_codes: Vec<String>,
+    min_population: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SourceFile {
+    path: String,
+    len: u64,
+    modified: SystemTime,
+}
+
+impl IndexKey {
+    pub fn new(config: &GeocoderConfig) -> Result<Self> {
+        let sources = config
+            .source_files()
+            .into_iter()
+            .map(|path| {
+                let metadata =
+                    std::fs::metadata(path).with_context(|| format!("Failed to read {}", path))?;
+                Ok(SourceFile {
+                    path: path.to_string(),
+                    len: metadata.len(),
+                    modified: metadata.modified()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(IndexKey {
+            sources,
+            ..Self::filters(config)
+        })
+    }
+
+    /// Key of the filters alone, for when the geonames files are not there
+    fn filters(config: &GeocoderConfig) -> Self {
+        IndexKey {
+            sources: Vec::new(),
+            feature_classes: config.feature_classes.clone(),
+            include_feature_codes: config.include_feature_codes.clone(),
+            exclude_feature_codes: config.exclude_feature_codes.clone(),
+            min_population: config.min_population,
+        }
+    }
+
+    fn same_filters(&self, other: &IndexKey) -> bool {
+        self.feature_classes == other.feature_classes
+            && self.include_feature_codes == other.include_feature_codes
+            && self.exclude_feature_codes == other.exclude_feature_codes
+            && self.min_population == other.min_population
+    }
+}
+
+/// Prebuilt geocoder index on disk, so processors do not have to parse the
+/// geonames dump on every start.
+pub struct GeoIndexFile {
+    path: PathBuf,
+}
+
+impl GeoIndexFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        GeoIndexFile { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The saved index if it was built for `key`, or `None` if there is no
+    /// index yet or it is stale
+    pub fn load(&self, key: &IndexKey) -> Result<Option<PlaceIndex>> {
+        self.load_if(|saved_key| saved_key == key)
+    }
+
+    /// The saved index if it was built with the filters in `config`, whatever
+    /// geonames files it was built from. For nodes that are shipped the index
+    /// without the dumps, which cannot tell whether it is up to date.
+    pub fn load_without_sources(&self, config: &GeocoderConfig) -> Result<Option<PlaceIndex>> {
+        let filters = IndexKey::filters(config);
+        self.load_if(|saved_key| saved_key.same_filters(&filters))
+    }
+
+    fn load_if(&self, matches: impl FnOnce(&IndexKey) -> bool) -> Result<Option<PlaceIndex>> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(anyhow!(
+                    "Failed to read geocoder index {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            }
+        };
+
+        let payload = check_header(&contents)
+            .with_context(|| format!("Invalid geocoder index {}", self.path.display()))?;
+        let mut payload = payload;
+        let saved_key: IndexKey = bincode::deserialize_from(&mut payload)?;
+        if !matches(&saved_key) {
+            return Ok(None);
+        }
+        let index = bincode::deserialize_from(payload)
+            .with_context(|| format!("Invalid geocoder index {}", self.path.display()))?;
+        Ok(Some(index))
+    }
+
+    /// Replace the saved index with `index`, built for `key`
+    pub fn save(&self, key: &IndexKey, index: &PlaceIndex) -> Result<()> {
+        let mut payload = bincode::serialize(key)?;
+        bincode::serialize_into(&mut payload, index)?;
+
+        let temp_path = self.path.with_extension("tmp");
+        let write = || -> Result<()> {
+            if let Some(parent) = self
+                .path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&temp_path)?);
+            file.write_all(MAGIC)?;
+            file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+            file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+            file.write_all(&payload)?;
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            std::fs::rename(&temp_path, &self.path)?;
+            Ok(())
+        };
+        write().with_context(|| format!("Failed to write geocoder index {}", self.path.display()))
+    }
+}
+
+/// The payload of an index file, once its header and checksum are verified
+fn check_header(contents: &[u8]) -> Result<&[u8]> {
+    if contents.len() < HEADER_LEN || !contents.starts_with(MAGIC) {
+        bail!("not a geocoder index");
+    }
+    let field = |at: usize| u32::from_le_bytes(contents[at..at + 4].try_into().unwrap());
+    let version = field(MAGIC.len());
+    if version != FORMAT_VERSION {
+        bail!(
+            "format version {} is not supported, expected {}",
+            version,
+            FORMAT_VERSION
+        );
+    }
+
+    let payload = &contents[HEADER_LEN..];
+    if crc32fast::hash(payload) != field(MAGIC.len() + 4) {
+        bail!("checksum mismatch");
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::H3Geocoder;
+    use std::path::Path;
+
+    struct Files {
+        geonames: PathBuf,
+        index: PathBuf,
+    }
+
+    impl Files {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir();
+            let id = std::process::id();
+            let files = Files {
+                geonames: dir.join(format!("geo-index-{}-{}.txt", name, id)),
+                index: dir.join(format!("geo-index-{}-{}.bin", name, id)),
+            };
+            let rows = [
+                "1\tLondon\tLondon\t\t51.50853\t-0.12574\tP\tPPLC\tGB\t\tENG\tGLA\t\t\t8961989\t\t25\tEurope/London\t2024-01-01",
+                "2\tParis\tParis\t\t48.85341\t2.3488\tP\tPPLC\tFR\t\t11\t75\t\t\t2138551\t\t42\tEurope/Paris\t2024-01-01",
+            ];
+            std::fs::write(&files.geonames, rows.join("\n")).unwrap();
+            files
+        }
+
+        fn config(&self, min_population: u64) -> GeocoderConfig {
+            serde_json::from_value(serde_json::json!({
+                "geonames_file_path": self.geonames,
+                "index_path": self.index,
+                "h3_resolutions": [5],
+                "feature_classes": ["P"],
+                "min_population": min_population,
+                "population_weight": 1.0,
+            }))
+            .unwrap()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            for path in [&self.geonames, &self.index] {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    fn save(config: &GeocoderConfig, path: &Path) {
+        let index = PlaceIndex::from_geonames_files(config).unwrap();
+        GeoIndexFile::new(path)
+            .save(&IndexKey::new(config).unwrap(), &index)
+            .unwrap();
+    }
+
+    #[test]
+    fn saved_index_loads_for_the_same_key_only() {
+        let files = Files::new("round-trip");
+        let config = files.config(0);
+        save(&config, &files.index);
+
+        let index_file = GeoIndexFile::new(&files.index);
+        let index = index_file
+            .load(&IndexKey::new(&config).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.place_count(), 2);
+
+        let filtered = files.config(5_000_000);
+        assert!(index_file
+            .load(&IndexKey::new(&filtered).unwrap())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn corrupt_index_is_rebuilt() {
+        let files = Files::new("corrupt");
+        let config = files.config(0);
+        save(&config, &files.index);
+
+        let mut contents = std::fs::read(&files.index).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        std::fs::write(&files.index, contents).unwrap();
+
+        let index_file = GeoIndexFile::new(&files.index);
+        let key = IndexKey::new(&config).unwrap();
+        let Err(error) = index_file.load(&key) else {
+            panic!("corrupt index loaded");
+        };
+        assert!(format!("{:#}", error).contains("checksum mismatch"));
+
+        let geocoder = H3Geocoder::load(&config).unwrap();
+        assert_eq!(geocoder.index().place_count(), 2);
+        assert!(index_file.load(&key).unwrap().is_some());
+    }
+
+    #[test]
+    fn index_is_used_without_the_geonames_files() {
+        let files = Files::new("without-sources");
+        let config = files.config(0);
+        save(&config, &files.index);
+        std::fs::remove_file(&files.geonames).unwrap();
+
+        let geocoder = H3Geocoder::load(&config).unwrap();
+        assert_eq!(geocoder.index().place_count(), 2);
+
+        // Built with other filters, and nothing to rebuild it from
+        assert!(H3Geocoder::load(&files.config(5_000_000)).is_err());
+    }
+}
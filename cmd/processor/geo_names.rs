@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use std::io::BufRead;
+use std::sync::Arc;
+
+use crate::config::GeocoderConfig;
+
+/// Country details from geonames countryInfo.txt
+#[derive(Debug, Clone)]
+pub struct CountryInfo {
+    pub name: Arc<str>,
+    pub iso3: Arc<str>,
+    /// Two-letter continent code, e.g. `EU`
+    pub continent: Arc<str>,
+}
+
+/// Human-readable names for the country and admin codes of geonames places,
+/// from whichever of countryInfo.txt, admin1CodesASCII.txt and
+/// admin2Codes.txt are configured.
+#[derive(Default)]
+pub struct AdminNames {
+    /// By ISO2 country code
+    pub countries: Vec<(String, CountryInfo)>,
+    /// By country and admin1 code, e.g. `GB` and `ENG`
+    pub admin1: Vec<([String; 2], Arc<str>)>,
+    /// By country, admin1 and admin2 code
+    pub admin2: Vec<([String; 3], Arc<str>)>,
+}
+
+impl AdminNames {
+    pub fn load(config: &GeocoderConfig) -> Result<Self> {
+        let mut names = AdminNames::default();
+
+        if let Some(path) = &config.country_info_path {
+            for_each_row(path, |fields| {
+                if let [iso2, iso3, _, _, name, _, _, _, continent, ..] = fields {
+                    names.countries.push((
+                        iso2.to_string(),
+                        CountryInfo {
+                            name: Arc::from(*name),
+                            iso3: Arc::from(*iso3),
+                            continent: Arc::from(*continent),
+                        },
+                    ));
+                }
+            })?;
+        }
+
+        if let Some(path) = &config.admin1_codes_path {
+            for_each_row(path, |fields| {
+                if let [code, name, ..] = fields {
+                    if let Some((country, admin1)) = code.split_once('.') {
+                        names
+                            .admin1
+                            .push(([country.to_string(), admin1.to_string()], Arc::from(*name)));
+                    }
+                }
+            })?;
+        }
+
+        if let Some(path) = &config.admin2_codes_path {
+            for_each_row(path, |fields| {
+                if let [code, name, ..] = fields {
+                    let mut parts = code.splitn(3, '.');
+                    if let (Some(country), Some(admin1), Some(admin2)) =
+                        (parts.next(), parts.next(), parts.next())
+                    {
+                        names.admin2.push((
+                            [country.to_string(), admin1.to_string(), admin2.to_string()],
+                            Arc::from(*name),
+                        ));
+                    }
+                }
+            })?;
+        }
+
+        Ok(names)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty() && self.admin1.is_empty() && self.admin2.is_empty()
+    }
+}
+
+/// Call `f` with the tab-separated fields of every row of a geonames file,
+/// skipping `#` comments
+fn for_each_row(path: &str, mut f: impl FnMut(&[&str])) -> Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        f(&fields);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("geo-names-{}-{}.txt", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn load(country_info: &str, admin1: &str, admin2: &str) -> AdminNames {
+        let paths = [
+            temp_file("countries", country_info),
+            temp_file("admin1", admin1),
+            temp_file("admin2", admin2),
+        ];
+        let config: GeocoderConfig = serde_json::from_value(serde_json::json!({
+            "h3_resolutions": [5],
+            "country_info_path": paths[0],
+            "admin1_codes_path": paths[1],
+            "admin2_codes_path": paths[2],
+            "min_population": 0,
+            "population_weight": 0.0,
+        }))
+        .unwrap();
+        let names = AdminNames::load(&config).unwrap();
+        for path in paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        names
+    }
+
+    #[test]
+    fn parses_countries_and_admin_codes() {
+        let names = load(
+            "# GeoNames.org Country Information\n\
+             #ISO\tISO3\tISO-Numeric\tfips\tCountry\tCapital\tArea(in sq km)\tPopulation\tContinent\n\
+             GB\tGBR\t826\tUK\tUnited Kingdom\tLondon\t244820\t66488991\tEU\t.uk\tGBP\n\
+             FR\tFRA\t250\tFR\tFrance\tParis\t547030\n\
+             \n\
+             BR\tBRA\t076\tBR\tBrazil\tBrasilia\t8511965\t209469333\tSA\n",
+            "GB.ENG\tEngland\tEngland\t6269131\n\
+             GBSCT\tScotland\tScotland\t2638360\n\
+             FR.11\n\
+             US.CA\tCalifornia\tCalifornia\t5332921\n",
+            "# admin2\n\
+             US.CA.037\tLos Angeles County\tLos Angeles County\t5368381\n\
+             GB.ENG\tNo admin2 code\n\
+             GB.ENG.E1.X\tCode with extra dots\tCode with extra dots\t0\n",
+        );
+
+        let countries: Vec<_> = names
+            .countries
+            .iter()
+            .map(|(iso2, info)| (iso2.as_str(), &*info.iso3, &*info.name, &*info.continent))
+            .collect();
+        assert_eq!(
+            countries,
+            [
+                ("GB", "GBR", "United Kingdom", "EU"),
+                ("BR", "BRA", "Brazil", "SA"),
+            ]
+        );
+
+        let admin1: Vec<_> = names
+            .admin1
+            .iter()
+            .map(|([country, code], name)| (country.as_str(), code.as_str(), &**name))
+            .collect();
+        assert_eq!(
+            admin1,
+            [("GB", "ENG", "England"), ("US", "CA", "California")]
+        );
+
+        let admin2: Vec<_> = names
+            .admin2
+            .iter()
+            .map(|([country, admin1, code], name)| {
+                (country.as_str(), admin1.as_str(), code.as_str(), &**name)
+            })
+            .collect();
+        assert_eq!(
+            admin2,
+            [
+                ("US", "CA", "037", "Los Angeles County"),
+                ("GB", "ENG", "E1.X", "Code with extra dots"),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_files_are_an_error() {
+        let config: GeocoderConfig = serde_json::from_value(serde_json::json!({
+            "h3_resolutions": [5],
+            "admin1_codes_path": "/nonexistent/admin1CodesASCII.txt",
+            "min_population": 0,
+            "population_weight": 0.0,
+        }))
+        .unwrap();
+        let Err(error) = AdminNames::load(&config) else {
+            panic!("loaded a missing file");
+        };
+        assert!(format!("{:#}", error).contains("admin1CodesASCII.txt"));
+    }
+}
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result};
+use geo::{Contains, MultiPolygon, Point};
+use h3o::geom::{ContainmentMode, TilerBuilder};
+use h3o::{LatLng, Resolution};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::boundaries::read_geojson;
+use crate::config::GeofenceConfig;
+
+/// A user-defined area of interest, such as a watershed or city district
+struct Zone {
+    layer: Arc<str>,
+    id: Arc<str>,
+    name: Option<Arc<str>>,
+    area: MultiPolygon,
+}
+
+/// A zone containing a point
+#[derive(Debug, Clone)]
+pub struct ZoneMatch {
+    pub layer: Arc<str>,
+    pub id: Arc<str>,
+    pub name: Option<Arc<str>>,
+}
+
+/// Zones by the H3 cells covering them. A point in a cell lying entirely
+/// inside a zone is in that zone; for cells on a zone's edge its polygon
+/// decides.
+pub struct GeofenceRegistry {
+    resolution: Resolution,
+    zones: Vec<Zone>,
+    /// Zones covering each cell, by index into `zones`, and whether the cell
+    /// lies entirely inside the zone
+    cells: HashMap<u64, Vec<(u32, bool)>>,
+}
+
+impl GeofenceRegistry {
+    pub fn load(config: &GeofenceConfig) -> Result<Self> {
+        let resolution =
+            Resolution::try_from(config.resolution).context("Invalid geofences.resolution")?;
+        let mut registry = GeofenceRegistry {
+            resolution,
+            zones: Vec::new(),
+            cells: HashMap::new(),
+        };
+
+        for file in &config.files {
+            let layer: Arc<str> = Arc::from(file.layer.as_str());
+            let features = read_geojson(&file.path)
+                .with_context(|| format!("Failed to load geofences from {}", file.path))?;
+            for (area, properties) in features {
+                let id = properties.get(&file.id_property).with_context(|| {
+                    format!("Geofence in {} without {}", file.path, file.id_property)
+                })?;
+                let name = file
+                    .name_property
+                    .as_ref()
+                    .and_then(|name| properties.get(name));
+                registry
+                    .add(Zone {
+                        layer: Arc::clone(&layer),
+                        id: Arc::from(id.as_str()),
+                        name: name.map(|name| Arc::from(name.as_str())),
+                        area,
+                    })
+                    .with_context(|| format!("Invalid geofence {} in {}", id, file.path))?;
+            }
+        }
+        Ok(registry)
+    }
+
+    fn add(&mut self, zone: Zone) -> Result<()> {
+        let index = u32::try_from(self.zones.len())?;
+        let tile = |mode| -> Result<Vec<u64>> {
+            let mut tiler = TilerBuilder::new(self.resolution)
+                .containment_mode(mode)
+                .build();
+            tiler.add_batch(zone.area.0.iter().cloned())?;
+            Ok(tiler.into_coverage().map(u64::from).collect())
+        };
+
+        let inside: HashSet<u64> = tile(ContainmentMode::ContainsBoundary)?
+            .into_iter()
+            .collect();
+        for cell in tile(ContainmentMode::Covers)? {
+            self.cells
+                .entry(cell)
+                .or_default()
+                .push((index, inside.contains(&cell)));
+        }
+
+        self.zones.push(zone);
+        Ok(())
+    }
+
+    pub fn zone_count(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// The zone containing the point in each layer, the first loaded where
+    /// zones of a layer overlap
+    pub fn zones_at(&self, lat: f64, lng: f64) -> Vec<ZoneMatch> {
+        let Ok(coord) = LatLng::new(lat, lng) else {
+            return Vec::new();
+        };
+        let Some(candidates) = self.cells.get(&u64::from(coord.to_cell(self.resolution))) else {
+            return Vec::new();
+        };
+
+        let point = Point::new(lng, lat);
+        let mut zones: Vec<ZoneMatch> = Vec::new();
+        for &(zone, whole) in candidates {
+            let zone = &self.zones[zone as usize];
+            if zones.iter().any(|found| found.layer == zone.layer)
+                || !(whole || zone.area.contains(&point))
+            {
+                continue;
+            }
+            zones.push(ZoneMatch {
+                layer: Arc::clone(&zone.layer),
+                id: Arc::clone(&zone.id),
+                name: zone.name.clone(),
+            });
+        }
+        zones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GeofenceFileConfig;
+
+    fn square(
+        id: serde_json::Value,
+        name: Option<&str>,
+        min: (f64, f64),
+        max: (f64, f64),
+    ) -> serde_json::Value {
+        let ((lng0, lat0), (lng1, lat1)) = (min, max);
+        serde_json::json!({
+            "type": "Feature",
+            "properties": { "id": id, "name": name },
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[[lng0, lat0], [lng1, lat0], [lng1, lat1], [lng0, lat1], [lng0, lat0]]]
+            }
+        })
+    }
+
+    fn registry() -> GeofenceRegistry {
+        let layers = [
+            (
+                "district",
+                vec![
+                    square("a".into(), Some("A"), (0.0, 0.0), (0.1, 0.1)),
+                    square(7.into(), None, (0.05, 0.0), (0.15, 0.1)),
+                ],
+            ),
+            (
+                "watershed",
+                vec![square("w".into(), Some("W"), (0.0, 0.0), (0.04, 0.04))],
+            ),
+        ];
+
+        let mut files = Vec::new();
+        for (layer, features) in layers {
+            let path = std::env::temp_dir().join(format!(
+                "geofence-{}-{}.geojson",
+                layer,
+                std::process::id()
+            ));
+            let collection =
+                serde_json::json!({ "type": "FeatureCollection", "features": features });
+            std::fs::write(&path, collection.to_string()).unwrap();
+            files.push(GeofenceFileConfig {
+                path: path.to_str().unwrap().to_string(),
+                layer: layer.to_string(),
+                id_property: "id".to_string(),
+                name_property: Some("name".to_string()),
+            });
+        }
+
+        let registry = GeofenceRegistry::load(&GeofenceConfig {
+            resolution: 9,
+            files,
+        });
+        for layer in ["district", "watershed"] {
+            let path = std::env::temp_dir().join(format!(
+                "geofence-{}-{}.geojson",
+                layer,
+                std::process::id()
+            ));
+            std::fs::remove_file(path).unwrap();
+        }
+        registry.unwrap()
+    }
+
+    fn zones(
+        registry: &GeofenceRegistry,
+        lat: f64,
+        lng: f64,
+    ) -> Vec<(String, String, Option<String>)> {
+        registry
+            .zones_at(lat, lng)
+            .into_iter()
+            .map(|zone| {
+                (
+                    zone.layer.to_string(),
+                    zone.id.to_string(),
+                    zone.name.map(|name| name.to_string()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn points_get_the_zone_containing_them_in_each_layer() {
+        let registry = registry();
+        assert_eq!(registry.zone_count(), 3);
+
+        assert_eq!(
+            zones(&registry, 0.02, 0.02),
+            [
+                ("district".into(), "a".into(), Some("A".into())),
+                ("watershed".into(), "w".into(), Some("W".into())),
+            ]
+        );
+        // Overlapping zones of a layer: the first loaded wins
+        assert_eq!(
+            zones(&registry, 0.05, 0.075),
+            [("district".into(), "a".into(), Some("A".into()))]
+        );
+        // Numeric ids are kept as their text
+        assert_eq!(
+            zones(&registry, 0.05, 0.125),
+            [("district".into(), "7".into(), None)]
+        );
+        assert!(zones(&registry, 0.05, 0.2).is_empty());
+    }
+
+    #[test]
+    fn polygons_decide_on_the_cells_along_a_zone_edge() {
+        let registry = registry();
+
+        // Either side of the edge of zone `a`, within the same H3 cell
+        let cell = |lng| LatLng::new(0.07, lng).unwrap().to_cell(Resolution::Nine);
+        assert_eq!(cell(0.0001), cell(-0.0001));
+        assert_eq!(zones(&registry, 0.07, 0.0001).len(), 1);
+        assert!(zones(&registry, 0.07, -0.0001).is_empty());
+    }
+}
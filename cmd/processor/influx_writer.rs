@@ -1,14 +1,19 @@
-use anyhow::{anyhow, Result};
-use futures::stream;
-use influxdb2::models::DataPoint;
-use influxdb2::Client;
+use anyhow::Result;
+use h3o::LatLng;
+use influxdb2::models::data_point::DataPointBuilder;
+use influxdb2::models::{DataPoint, WriteDataPoint};
+use influxdb2::{Client, RequestError};
 use tracing::{debug, error, info};
 
+use crate::aggregation::{LateReading, WindowAggregate};
 use crate::config::InfluxDbConfig;
-use crate::processor::ProcessedPoint;
+use crate::metrics::CounterKey;
+use crate::processor::{EnrichedData, ProcessedPoint, RejectedPoint};
+use crate::proto;
 
 pub struct InfluxWriter {
     client: Client,
+    org: String,
     bucket: String,
 }
 
@@ -23,52 +28,134 @@ impl InfluxWriter {
 
         Ok(InfluxWriter {
             client,
+            org: config.org.clone(),
             bucket: config.bucket.clone(),
         })
     }
 
-    pub async fn write_points(&self, points: Vec<ProcessedPoint>) -> Result<()> {
-        if points.is_empty() {
+    /// Serialize processed points, and the validation failures of rejected
+    /// ones, into an InfluxDB line protocol request body
+    pub fn to_line_protocol(
+        &self,
+        points: &[ProcessedPoint],
+        rejections: &[RejectedPoint],
+        aggregates: &[WindowAggregate],
+        late: &[LateReading],
+    ) -> Result<Vec<u8>> {
+        let mut data_points = self.build_data_points(points)?;
+        data_points.extend(self.build_rejection_points(rejections)?);
+        data_points.extend(self.build_aggregate_points(aggregates)?);
+        data_points.extend(self.build_late_points(late)?);
+
+        let mut body = Vec::new();
+        for data_point in data_points {
+            data_point.write_data_point_to(&mut body)?;
+        }
+        Ok(body)
+    }
+
+    /// Serialize pipeline counters as `processor_metrics` points stamped now
+    pub fn metrics_to_line_protocol(&self, counters: &[(CounterKey, u64)]) -> Result<Vec<u8>> {
+        let timestamp = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+
+        let mut body = Vec::new();
+        for (key, count) in counters {
+            let mut builder = DataPoint::builder("processor_metrics")
+                .tag("metric", key.name)
+                .field("count", *count as i64)
+                .timestamp(timestamp);
+            for (label, value) in &key.labels {
+                builder = builder.tag(*label, value);
+            }
+            builder.build()?.write_data_point_to(&mut body)?;
+        }
+        Ok(body)
+    }
+
+    pub async fn write_line_protocol(&self, body: Vec<u8>) -> Result<()> {
+        if body.is_empty() {
             return Ok(());
         }
 
-        debug!("📝 Writing {} points to InfluxDB", points.len());
+        let line_count = body.iter().filter(|&&b| b == b'\n').count();
+        debug!("📝 Writing {} points to InfluxDB", line_count);
+
+        match self
+            .client
+            .write_line_protocol(&self.org, &self.bucket, body)
+            .await
+        {
+            Ok(_) => {
+                info!("✅ Successfully wrote {} points to InfluxDB", line_count);
+                Ok(())
+            }
+            Err(e) => {
+                error!("❌ Failed to write points to InfluxDB: {}", e);
+                Err(anyhow::Error::new(e).context("Failed to write to InfluxDB"))
+            }
+        }
+    }
 
+    fn build_data_points(&self, points: &[ProcessedPoint]) -> Result<Vec<DataPoint>> {
         let mut data_points = Vec::new();
 
-        for processed_point in &points {
+        for processed_point in points {
             let point = &processed_point.data_point;
             let enriched = &processed_point.enriched_data;
+            let quality = &processed_point.quality;
 
             // Convert epoch milliseconds to timestamp
             let timestamp = point.epoch_ms * 1_000_000; // Convert to nanoseconds
 
             // Create the main data point
             let mut builder = DataPoint::builder("data_points")
-                .tag("source", &point.source)
-                .tag("category", &point.category)
-                .tag("variable", &point.variable)
-                .tag("units", &point.units)
                 .tag("country", enriched.country.as_deref().unwrap_or("unknown"))
                 .tag("region", enriched.region.as_deref().unwrap_or("unknown"))
-                .field("value", point.value)
+                .tag("qc_flag", quality.flag.as_str())
                 .field("lat", point.lat)
                 .field("lon", point.lon)
                 .timestamp(timestamp);
+            builder = point_tags(builder, point);
+            builder = location_tags(builder, enriched);
+
+            // Missing values have no line protocol representation
+            if point.value.is_finite() {
+                builder = builder.field("value", point.value);
+            }
+
+            if !quality.failed_tests.is_empty() {
+                builder = builder.field("qc_failed_tests", quality.failed_tests.join(","));
+            }
+
+            // Fields rather than tags, so converted readings stay in the same series
+            if let Some(original) = &processed_point.original {
+                if original.value.is_finite() {
+                    builder = builder.field("original_value", original.value);
+                }
+                builder = builder.field("original_units", original.units.as_str());
+            }
 
             // Add H3 cell information if available
             if let Some(h3_cells) = &enriched.h3_cells {
-                for (resolution, &cell_id) in h3_cells.iter().enumerate() {
+                for &(resolution, cell_id) in h3_cells {
                     builder = builder.field(format!("h3_cell_res_{resolution}"), cell_id as i64);
                 }
             }
 
             // Add other enriched fields
-            if let Some(nearest_place) = &enriched.nearest_place {
+            if let Some(nearest_place) = enriched.nearest_place.as_deref() {
                 builder = builder.tag("nearest_place", nearest_place);
             }
 
-            if let Some(timezone) = &enriched.timezone {
+            if let Some(distance_km) = enriched.nearest_place_distance_km {
+                builder = builder.field("nearest_place_distance_km", distance_km);
+            }
+
+            if let Some(bearing_deg) = enriched.nearest_place_bearing_deg {
+                builder = builder.field("nearest_place_bearing_deg", bearing_deg);
+            }
+
+            if let Some(timezone) = enriched.timezone.as_deref() {
                 builder = builder.tag("timezone", timezone);
             }
 
@@ -89,18 +176,19 @@ impl InfluxWriter {
                     .field("lat", point.lat)
                     .field("lon", point.lon)
                     .timestamp(timestamp);
+                h3_builder = location_tags(h3_builder, enriched);
 
                 // Add all H3 cell IDs as fields for efficient spatial queries
-                for (resolution, &cell_id) in h3_cells.iter().enumerate() {
+                for &(resolution, cell_id) in h3_cells {
                     h3_builder =
                         h3_builder.field(format!("h3_cell_res_{resolution}"), cell_id as i64);
                 }
 
-                if let Some(nearest_place) = &enriched.nearest_place {
+                if let Some(nearest_place) = enriched.nearest_place.as_deref() {
                     h3_builder = h3_builder.tag("nearest_place", nearest_place);
                 }
 
-                if let Some(timezone) = &enriched.timezone {
+                if let Some(timezone) = enriched.timezone.as_deref() {
                     h3_builder = h3_builder.tag("timezone", timezone);
                 }
 
@@ -128,21 +216,22 @@ impl InfluxWriter {
                     .field("lat", point.lat)
                     .field("lon", point.lon)
                     .timestamp(timestamp);
+                calculated_builder = location_tags(calculated_builder, enriched);
 
                 // Add H3 cell information to calculated fields too
                 if let Some(h3_cells) = &enriched.h3_cells {
-                    for (resolution, &cell_id) in h3_cells.iter().enumerate() {
+                    for &(resolution, cell_id) in h3_cells {
                         calculated_builder = calculated_builder
                             .field(format!("h3_cell_res_{resolution}"), cell_id as i64);
                     }
                 }
 
                 // Add other enriched fields
-                if let Some(nearest_place) = &enriched.nearest_place {
+                if let Some(nearest_place) = enriched.nearest_place.as_deref() {
                     calculated_builder = calculated_builder.tag("nearest_place", nearest_place);
                 }
 
-                if let Some(timezone) = &enriched.timezone {
+                if let Some(timezone) = enriched.timezone.as_deref() {
                     calculated_builder = calculated_builder.tag("timezone", timezone);
                 }
 
@@ -155,21 +244,123 @@ impl InfluxWriter {
             }
         }
 
-        // Write all points at once
-        match self
-            .client
-            .write(&self.bucket, stream::iter(data_points))
-            .await
-        {
-            Ok(_) => {
-                info!("✅ Successfully wrote {} points to InfluxDB", points.len());
-                Ok(())
+        Ok(data_points)
+    }
+
+    /// One `rejections` point per failed check, at the time of the reading
+    fn build_rejection_points(&self, rejections: &[RejectedPoint]) -> Result<Vec<DataPoint>> {
+        let mut data_points = Vec::new();
+
+        for rejected in rejections {
+            let point = &rejected.data_point;
+
+            for failure in &rejected.report.failures {
+                let mut builder = DataPoint::builder("rejections")
+                    .tag("rule_id", &failure.rule_id)
+                    .tag("check", failure.check)
+                    .tag("field", failure.field)
+                    .tag("severity", failure.severity.as_str())
+                    .field("message", failure.to_string())
+                    .timestamp(point.epoch_ms * 1_000_000);
+                // Points are rejected precisely for leaving these empty
+                builder = point_tags(builder, point);
+
+                // Line protocol has no representation for NaN or infinity
+                let numeric_fields = [
+                    ("observed", failure.observed),
+                    ("expected_min", failure.expected_min),
+                    ("expected_max", failure.expected_max),
+                    ("lat", Some(point.lat)),
+                    ("lon", Some(point.lon)),
+                ];
+                for (name, value) in numeric_fields {
+                    if let Some(value) = value.filter(|v| v.is_finite()) {
+                        builder = builder.field(name, value);
+                    }
+                }
+
+                data_points.push(builder.build()?);
             }
-            Err(e) => {
-                error!("❌ Failed to write points to InfluxDB: {}", e);
-                Err(anyhow!("Failed to write to InfluxDB: {}", e))
+        }
+
+        Ok(data_points)
+    }
+
+    /// One point per closed window, at the start of the window: per-source
+    /// series go to `aggregates`, spatial rollups across sources to
+    /// `h3_rollups` with the cell centre for map rendering
+    fn build_aggregate_points(&self, aggregates: &[WindowAggregate]) -> Result<Vec<DataPoint>> {
+        let mut data_points = Vec::new();
+
+        for aggregate in aggregates {
+            let stats = &aggregate.stats;
+            let measurement = match aggregate.source {
+                Some(_) => "aggregates",
+                None => "h3_rollups",
+            };
+            let mut builder = DataPoint::builder(measurement)
+                .tag("category", &aggregate.category)
+                .tag("variable", &aggregate.variable)
+                .tag("window", &aggregate.window)
+                .tag("h3_cell", aggregate.cell.to_string())
+                .tag(
+                    "h3_resolution",
+                    u8::from(aggregate.cell.resolution()).to_string(),
+                )
+                .field("count", stats.count as i64)
+                .field("min", stats.min)
+                .field("max", stats.max)
+                .field("mean", stats.mean())
+                .field("sum", stats.sum)
+                .field("last", stats.last)
+                .field("window_end_ms", aggregate.end_ms)
+                .field("revision", aggregate.revision as i64)
+                .timestamp(aggregate.start_ms * 1_000_000);
+            builder = tag_if_present(builder, "units", &stats.units);
+
+            if let Some(local_date) = aggregate.local_date {
+                builder = builder.tag("local_date", local_date.to_string());
+            }
+
+            for (name, value) in &aggregate.percentiles {
+                builder = builder.field(name.as_str(), *value);
             }
+
+            match &aggregate.source {
+                Some(source) => builder = builder.tag("source", source),
+                None => {
+                    let centre = LatLng::from(aggregate.cell);
+                    builder = builder
+                        .field("lat", centre.lat())
+                        .field("lon", centre.lng());
+                }
+            }
+
+            data_points.push(builder.build()?);
         }
+
+        Ok(data_points)
+    }
+
+    /// One `late_data` point per window a reading came too late for
+    fn build_late_points(&self, late: &[LateReading]) -> Result<Vec<DataPoint>> {
+        let mut data_points = Vec::new();
+
+        for reading in late {
+            let point = &reading.data_point;
+            let mut builder = DataPoint::builder("late_data")
+                .tag("window", &reading.window)
+                .field("lateness_ms", reading.lateness_ms)
+                .timestamp(point.epoch_ms * 1_000_000);
+            builder = point_tags(builder, point);
+            if point.value.is_finite() {
+                builder = builder.field("value", point.value);
+            }
+
+            data_points.push(builder.build()?);
+        }
+
+        Ok(data_points)
     }
 
     fn get_units_for_calculated_field(&self, field_name: &str, category: &str) -> String {
@@ -202,3 +393,213 @@ impl InfluxWriter {
         }
     }
 }
+
+/// Add a tag unless its value is empty, which line protocol cannot represent
+/// and InfluxDB rejects the whole write for
+fn tag_if_present(builder: DataPointBuilder, key: &str, value: &str) -> DataPointBuilder {
+    if value.is_empty() {
+        builder
+    } else {
+        builder.tag(key, value)
+    }
+}
+
+/// The source, category, variable and units tags of a reading, where set
+fn point_tags(mut builder: DataPointBuilder, point: &proto::DataPoint) -> DataPointBuilder {
+    let tags = [
+        ("source", &point.source),
+        ("category", &point.category),
+        ("variable", &point.variable),
+        ("units", &point.units),
+    ];
+    for (key, value) in tags {
+        builder = tag_if_present(builder, key, value);
+    }
+    builder
+}
+
+/// Tags for the admin2 code, the names of the country and admin codes, the
+/// marine region and any geofenced zones, where known. Each zone layer gets
+/// its own `zone_<layer>` and `zone_<layer>_name` tags.
+fn location_tags(mut builder: DataPointBuilder, enriched: &EnrichedData) -> DataPointBuilder {
+    let tags = [
+        ("admin2", &enriched.admin2),
+        ("country_name", &enriched.country_name),
+        ("country_iso3", &enriched.country_iso3),
+        ("continent", &enriched.continent),
+        ("region_name", &enriched.region_name),
+        ("admin2_name", &enriched.admin2_name),
+        ("marine_region", &enriched.marine_region),
+    ];
+    for (key, value) in tags {
+        if let Some(value) = value.as_deref() {
+            builder = builder.tag(key, value);
+        }
+    }
+
+    for zone in &enriched.zones {
+        builder = builder.tag(format!("zone_{}", zone.layer), &*zone.id);
+        if let Some(name) = zone.name.as_deref() {
+            builder = builder.tag(format!("zone_{}_name", zone.layer), name);
+        }
+    }
+    builder
+}
+
+/// Whether a failed write is worth retrying (server unreachable, overloaded
+/// or misconfigured) rather than a permanent rejection of the points themselves.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<RequestError>() {
+        Some(RequestError::ReqwestProcessing { .. }) => true,
+        Some(RequestError::Http { status, .. }) => is_retryable_status(status.as_u16()),
+        _ => false,
+    }
+}
+
+/// A bad token (401, 403) or a missing org or bucket (404) is a problem with
+/// the configuration, not the points, so writes are held back until it is
+/// fixed instead of the points being given up on.
+fn is_retryable_status(status: u16) -> bool {
+    matches!(status, 401 | 403 | 404 | 429 | 500..=599)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geofence::ZoneMatch;
+    use crate::validation::{ValidationFailure, ValidationReport};
+    use std::sync::Arc;
+
+    async fn writer() -> InfluxWriter {
+        InfluxWriter::new(&InfluxDbConfig {
+            host: "localhost".to_string(),
+            port: 8086,
+            org: "emma".to_string(),
+            bucket: "test".to_string(),
+            token: String::new(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejections_leave_out_empty_tags() {
+        let data_point = proto::DataPoint {
+            source: String::new(),
+            epoch_ms: 1_700_000_000_000,
+            value: 21.5,
+            lat: 51.5,
+            lon: -0.1,
+            variable: "temperature".to_string(),
+            units: String::new(),
+            category: "environmental".to_string(),
+            ..Default::default()
+        };
+        let mut report = ValidationReport::default();
+        report.push(ValidationFailure::new("required", "present", "source"));
+        let rejected = RejectedPoint {
+            data_point: data_point.clone(),
+            report,
+        };
+        let late = LateReading {
+            window: "1h".to_string(),
+            lateness_ms: 5_000,
+            data_point,
+        };
+
+        let body = writer()
+            .await
+            .to_line_protocol(&[], &[rejected], &[], &[late])
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("rejections,"));
+        assert!(lines[1].starts_with("late_data,"));
+        for line in lines {
+            assert!(line.contains("variable=temperature"), "{line}");
+            assert!(!line.contains("source="), "{line}");
+            assert!(!line.contains("units="), "{line}");
+        }
+    }
+
+    #[test]
+    fn zones_are_tagged_per_layer() {
+        let zone = |layer: &str, id: &str, name: Option<&str>| ZoneMatch {
+            layer: Arc::from(layer),
+            id: Arc::from(id),
+            name: name.map(Arc::from),
+        };
+        let enriched = EnrichedData {
+            zones: vec![
+                zone("watershed", "thames", Some("Thames")),
+                zone("district", "42", None),
+            ],
+            ..Default::default()
+        };
+
+        let builder = DataPoint::builder("measurements").field("value", 1.0);
+        let mut body = Vec::new();
+        location_tags(builder, &enriched)
+            .build()
+            .unwrap()
+            .write_data_point_to(&mut body)
+            .unwrap();
+        let line = String::from_utf8(body).unwrap();
+
+        assert!(line.contains("zone_watershed=thames"), "{line}");
+        assert!(line.contains("zone_watershed_name=Thames"), "{line}");
+        assert!(line.contains("zone_district=42"), "{line}");
+        assert!(!line.contains("zone_district_name"), "{line}");
+    }
+
+    #[test]
+    fn only_rejections_of_the_points_are_permanent() {
+        // Server errors and overload
+        for status in [500, 502, 503, 504, 429] {
+            assert!(is_retryable_status(status), "{status}");
+        }
+        // Bad token, missing org or bucket
+        for status in [401, 403, 404] {
+            assert!(is_retryable_status(status), "{status}");
+        }
+        // Malformed or oversized points
+        for status in [400, 413, 422] {
+            assert!(!is_retryable_status(status), "{status}");
+        }
+        // Points that cannot even be serialized
+        assert!(!is_retryable(&anyhow::anyhow!("invalid field value")));
+    }
+
+    #[tokio::test]
+    async fn local_day_aggregates_are_tagged_with_their_date() {
+        use crate::aggregation::test_support::{config, reading, HOUR};
+        use crate::aggregation::Aggregator;
+
+        // 2018-11-04 in Sao Paulo, 23 hours long
+        let start = 1_541_300_400_000;
+        let mut aggregator = Aggregator::new(&config(&["local_day"])).unwrap();
+        let timezone = Some("America/Sao_Paulo");
+        for (offset, epoch_ms) in [start + HOUR, start + 23 * HOUR].into_iter().enumerate() {
+            aggregator
+                .add(
+                    &reading("station", 20.0, epoch_ms),
+                    timezone,
+                    0,
+                    offset as i64,
+                )
+                .unwrap();
+        }
+        let aggregates = aggregator.take_closed();
+
+        let body = writer()
+            .await
+            .to_line_protocol(&[], &[], &aggregates, &[])
+            .unwrap();
+        let line = String::from_utf8(body).unwrap();
+        assert!(line.starts_with("aggregates,"), "{line}");
+        assert!(line.contains("local_date=2018-11-04"), "{line}");
+        assert!(line.contains("window=local_day"), "{line}");
+    }
+}
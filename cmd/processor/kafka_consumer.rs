@@ -2,10 +2,10 @@ use anyhow::{anyhow, Result};
 use futures::stream::BoxStream;
 use prost::Message as ProstMessage;
 use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::message::BorrowedMessage;
+use rdkafka::message::OwnedMessage;
 use rdkafka::{ClientConfig, Message};
 use tokio_stream::StreamExt;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 use crate::config::KafkaConfig;
 use crate::proto::DataPoint;
@@ -15,12 +15,27 @@ pub struct KafkaConsumer {
     topic: String,
 }
 
+/// Location of a consumed message, used to store its offset once handled
+#[derive(Debug, Clone)]
+pub struct MessagePosition {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
 impl KafkaConsumer {
     pub fn new(config: &KafkaConfig) -> Result<Self> {
+        // Offsets are stored manually once a message has been durably handled
+        // and committed in the background by librdkafka every commit interval.
         let consumer: StreamConsumer = ClientConfig::new()
             .set("bootstrap.servers", &config.bootstrap_servers)
             .set("group.id", &config.group_id)
             .set("enable.auto.commit", "true")
+            .set("enable.auto.offset.store", "false")
+            .set(
+                "auto.commit.interval.ms",
+                config.commit_interval_ms.to_string(),
+            )
             .set("auto.offset.reset", &config.auto_offset_reset)
             .set("enable.partition.eof", "false")
             .create()
@@ -32,7 +47,9 @@ impl KafkaConsumer {
         })
     }
 
-    pub async fn stream(&self) -> Result<BoxStream<'_, Result<DataPoint>>> {
+    /// Stream raw messages; decoding is left to `parse_message` so that
+    /// undecodable payloads can still be dead-lettered and committed.
+    pub async fn stream(&self) -> Result<BoxStream<'_, Result<OwnedMessage>>> {
         self.consumer
             .subscribe(&[&self.topic])
             .map_err(|e| anyhow!("Failed to subscribe to topic {}: {}", self.topic, e))?;
@@ -43,7 +60,7 @@ impl KafkaConsumer {
             .consumer
             .stream()
             .map(|message_result| match message_result {
-                Ok(message) => self.parse_message(message),
+                Ok(message) => Ok(message.detach()),
                 Err(e) => {
                     error!("Kafka message error: {}", e);
                     Err(anyhow!("Kafka message error: {}", e))
@@ -53,7 +70,29 @@ impl KafkaConsumer {
         Ok(Box::pin(message_stream))
     }
 
-    fn parse_message(&self, message: BorrowedMessage) -> Result<DataPoint> {
+    /// Mark a message as handled so its offset is included in the next commit
+    pub fn store_offset(&self, position: &MessagePosition) -> Result<()> {
+        self.consumer
+            .store_offset(&position.topic, position.partition, position.offset)
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to store offset {} for {}[{}]: {}",
+                    position.offset,
+                    position.topic,
+                    position.partition,
+                    e
+                )
+            })?;
+
+        debug!(
+            "📌 Stored offset {} for {}[{}]",
+            position.offset, position.topic, position.partition
+        );
+
+        Ok(())
+    }
+
+    pub fn parse_message(&self, message: &OwnedMessage) -> Result<DataPoint> {
         let payload = message
             .payload()
             .ok_or_else(|| anyhow!("Message has no payload"))?;
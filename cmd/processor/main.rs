@@ -1,79 +1,272 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 use tracing::{error, info};
 
+mod aggregation;
+mod batcher;
+mod boundaries;
+mod checkpoint;
 mod config;
+mod dead_letter;
 mod geo;
+mod geo_index;
+mod geo_names;
+mod geofence;
 mod influx_writer;
 mod kafka_consumer;
+mod metrics;
+mod pipeline;
 mod processor;
 mod proto;
+mod spool;
+mod units;
+mod validation;
 
-use geo::H3Geocoder;
+use geo::{H3Geocoder, PlaceIndex};
 
-use config::ProcessorConfig;
+use batcher::PointBatcher;
+use config::{GeocoderConfig, ProcessorConfig, CONFIG_FILE_ENV};
+use dead_letter::DeadLetterProducer;
+use geo_index::{GeoIndexFile, IndexKey};
+use geofence::GeofenceRegistry;
 use influx_writer::InfluxWriter;
 use kafka_consumer::KafkaConsumer;
+use metrics::Metrics;
+use pipeline::Pipeline;
 use processor::DataProcessor;
+use spool::Spool;
+use units::UnitRegistry;
+
+#[derive(Parser)]
+#[command(about = "Processes data points from Kafka into InfluxDB")]
+struct Cli {
+    /// YAML or TOML configuration file, overridden by PROCESSOR_* variables
+    #[arg(long, env = CONFIG_FILE_ENV)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build the geocoder index from the geonames file and exit
+    BuildIndex {
+        /// Where to write the index, instead of geocoder.index_path
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
+    let cli = Cli::parse();
+
     info!("🦀 Starting Data Processor...");
 
-    let config = ProcessorConfig::load()?;
-    info!("📝 Loaded configuration");
+    let config = ProcessorConfig::load(cli.config.as_deref())?;
+    match &cli.config {
+        Some(path) => info!("📝 Loaded configuration from {}", path.display()),
+        None => info!("📝 Loaded configuration"),
+    }
+
+    if let Some(Command::BuildIndex { output }) = cli.command {
+        return build_index(&config.geocoder, output);
+    }
 
     // Initialize H3 geocoder first
     info!(
         "🗺️ Loading geo location data from: {}",
-        config.geocoder.geonames_file_path
+        config.geocoder.source_files().join(", ")
     );
-    let geocoder = match H3Geocoder::from_geonames_file(&config.geocoder.geonames_file_path) {
+    let geocoder = match H3Geocoder::load(&config.geocoder) {
         Ok(geocoder) => {
-            info!("✅ Geo location data loaded successfully");
+            let index = geocoder.index();
+            info!(
+                "✅ Geo location data loaded: {} places, {} distinct strings, {:.1} MiB",
+                index.place_count(),
+                index.string_count(),
+                index.memory_usage() as f64 / (1024.0 * 1024.0)
+            );
             geocoder
         }
         Err(e) => {
-            error!("❌ Failed to load geo location data: {}", e);
-            return Err(anyhow::anyhow!("Failed to load geo location data: {}", e));
+            error!("❌ Failed to load geo location data: {:#}", e);
+            return Err(e.context("Failed to load geo location data"));
         }
     };
 
+    let geofences = GeofenceRegistry::load(&config.geofences)?;
+    if geofences.zone_count() > 0 {
+        info!(
+            "🗺️ Loaded {} geofenced zones covering {} H3 cells",
+            geofences.zone_count(),
+            geofences.cell_count()
+        );
+    }
+
     // Initialize components
     let kafka_consumer = KafkaConsumer::new(&config.kafka)?;
-    let processor = DataProcessor::new(&config.processing, geocoder);
-    let influx_writer = InfluxWriter::new(&config.influxdb).await?;
+    let dead_letter = DeadLetterProducer::new(&config.kafka)?;
+    let metrics = Arc::new(Metrics::default());
+    let units = UnitRegistry::new(&config.units)?;
+    let processor = DataProcessor::new(
+        &config.processing,
+        geocoder,
+        geofences,
+        units,
+        Arc::clone(&metrics),
+    )?;
+    let influx_writer = Arc::new(InfluxWriter::new(&config.influxdb).await?);
+    let spool = Spool::open(&config.spool)?;
+    spool.spawn_replay(Arc::clone(&influx_writer));
+    metrics.spawn_reporter(
+        Arc::clone(&influx_writer),
+        Duration::from_millis(config.metrics.report_interval_ms),
+    );
 
     info!("🔌 Connected to Kafka and InfluxDB");
 
     let mut message_stream = kafka_consumer.stream().await?;
+    let mut pipeline = Pipeline {
+        kafka_consumer: &kafka_consumer,
+        processor,
+        influx_writer,
+        spool,
+        dead_letter,
+        metrics,
+        batcher: PointBatcher::new(&config.processing),
+        pending_offsets: HashMap::new(),
+        store_interval: Duration::from_millis(config.kafka.commit_interval_ms),
+        last_stored: Instant::now(),
+    };
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     info!("🚀 Processing pipeline started");
 
-    // Main processing loop
-    while let Some(message) = message_stream.next().await {
-        match message {
-            Ok(data_point) => {
-                // Process the data point
-                match processor.process(data_point).await {
-                    Ok(processed_points) => {
-                        // Write to InfluxDB
-                        if let Err(e) = influx_writer.write_points(processed_points).await {
-                            error!("Failed to write to InfluxDB: {}", e);
-                        }
-                    }
+    // Main processing loop. A message that can be neither handled nor
+    // dead-lettered stops it, so no offset past that message is stored.
+    let mut stopped = Ok(());
+    loop {
+        let deadline = pipeline.deadline();
+
+        tokio::select! {
+            message = message_stream.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let message = match message {
+                    Ok(message) => message,
                     Err(e) => {
-                        error!("Failed to process data point: {}", e);
+                        error!("Kafka consumer error: {}", e);
+                        continue;
                     }
+                };
+
+                let mut result = pipeline.handle_message(message).await;
+                if result.is_ok() && pipeline.batcher.is_full() {
+                    result = pipeline.flush_batch().await;
+                }
+                if let Err(e) = result {
+                    error!("❌ {:#}, stopping", e);
+                    stopped = Err(e);
+                    break;
                 }
             }
-            Err(e) => {
-                error!("Kafka consumer error: {}", e);
+            _ = sleep_until(deadline) => {
+                if let Err(e) = pipeline.flush_batch().await {
+                    error!("❌ {:#}, stopping", e);
+                    stopped = Err(e);
+                    break;
+                }
+            }
+            _ = &mut shutdown => {
+                info!("🛑 Shutdown requested, flushing pending batch");
+                break;
             }
         }
     }
 
+    // Stored offsets are committed when the consumer is closed on drop
+    if let Err(e) = pipeline.flush_batch().await {
+        error!("❌ {:#}", e);
+    }
+    let stored = pipeline.store_offsets().await;
+    if let Err(e) = &stored {
+        error!("❌ Not storing offsets: {:#}", e);
+    }
+
+    let open_windows = pipeline.processor.open_windows();
+    if open_windows > 0 {
+        match &config.processing.aggregation.checkpoint_path {
+            Some(path) if stored.is_ok() => info!(
+                "💾 {} open aggregation windows saved in {}",
+                open_windows, path
+            ),
+            Some(path) => error!(
+                "❌ Failed to save {} open aggregation windows in {}",
+                open_windows, path
+            ),
+            None => info!("📊 Discarding {} open aggregation windows", open_windows),
+        }
+    }
+
+    stopped.context("Stopped consuming")
+}
+
+/// Build the geocoder index from the geonames file and save it
+fn build_index(config: &GeocoderConfig, output: Option<PathBuf>) -> Result<()> {
+    let Some(path) = output.or_else(|| config.index_path.as_ref().map(PathBuf::from)) else {
+        anyhow::bail!("No index path: set geocoder.index_path or pass --output");
+    };
+
+    let key = IndexKey::new(config)?;
+    let index = PlaceIndex::from_geonames_files(config)?;
+    let index_file = GeoIndexFile::new(path);
+    index_file.save(&key, &index)?;
+    info!(
+        "✅ Geocoder index written to {}",
+        index_file.path().display()
+    );
     Ok(())
 }
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
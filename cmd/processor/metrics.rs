@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::influx_writer::InfluxWriter;
+
+/// Identifies a counter: a metric name plus label values
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CounterKey {
+    pub name: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+}
+
+/// Cumulative counters of notable pipeline events, periodically logged and
+/// written to InfluxDB so they can be graphed alongside the data.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<CounterKey, u64>>,
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        let key = CounterKey {
+            name,
+            labels: labels
+                .iter()
+                .map(|(label, value)| (*label, value.to_string()))
+                .collect(),
+        };
+        *self.counters.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    pub fn snapshot(&self) -> Vec<(CounterKey, u64)> {
+        self.counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect()
+    }
+
+    /// Report the counters every `interval`, forever
+    pub fn spawn_reporter(
+        self: &Arc<Self>,
+        influx_writer: Arc<InfluxWriter>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let metrics = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                let counters = metrics.snapshot();
+                if counters.is_empty() {
+                    continue;
+                }
+
+                let summary: Vec<String> = counters
+                    .iter()
+                    .map(|(key, count)| {
+                        let labels: Vec<String> = key
+                            .labels
+                            .iter()
+                            .map(|(label, value)| format!("{label}={value}"))
+                            .collect();
+                        format!("{}{{{}}}={}", key.name, labels.join(","), count)
+                    })
+                    .collect();
+                info!("📈 Metrics: {}", summary.join(" "));
+
+                // Best effort: the next report carries the same cumulative counts
+                let result = match influx_writer.metrics_to_line_protocol(&counters) {
+                    Ok(body) => influx_writer.write_line_protocol(body).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Failed to write metrics to InfluxDB: {:#}", e);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::{anyhow, Result};
+use rdkafka::message::OwnedMessage;
+use rdkafka::Message;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+use crate::batcher::{Batch, PointBatcher};
+use crate::dead_letter::{DeadLetterProducer, FailureStage};
+use crate::influx_writer::{self, InfluxWriter};
+use crate::kafka_consumer::{KafkaConsumer, MessagePosition};
+use crate::metrics::Metrics;
+use crate::processor::{DataProcessor, ProcessOutcome};
+use crate::spool::Spool;
+use crate::validation::ValidationReport;
+
+/// Attempts at dead-lettering a message before giving up on consuming
+const DEAD_LETTER_ATTEMPTS: u32 = 5;
+/// Delay before the second attempt, doubling for each one after
+const DEAD_LETTER_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Moves consumed messages through processing and batching into InfluxDB,
+/// storing their offsets only once the data and aggregation state are durable.
+pub struct Pipeline<'a> {
+    pub kafka_consumer: &'a KafkaConsumer,
+    pub processor: DataProcessor,
+    pub influx_writer: Arc<InfluxWriter>,
+    pub spool: Arc<Spool>,
+    pub dead_letter: Option<DeadLetterProducer>,
+    pub metrics: Arc<Metrics>,
+    pub batcher: PointBatcher,
+    /// Offsets of written messages still to be stored, by (topic, partition)
+    pub pending_offsets: HashMap<(String, i32), i64>,
+    /// Offsets are stored, after checkpointing the aggregation state they
+    /// reflect, at most this often; Kafka only commits them that often anyway
+    pub store_interval: Duration,
+    pub last_stored: Instant,
+}
+
+impl Pipeline<'_> {
+    /// Run a single message through the pipeline and add its points to the
+    /// batch. Anything that cannot be handled is dead-lettered (when
+    /// configured) so the message can be committed either way. An error means
+    /// the dead-letter topic is unreachable; the message is left untracked,
+    /// so consuming must stop before any later offset is stored.
+    pub async fn handle_message(&mut self, message: OwnedMessage) -> Result<()> {
+        let (stage, error) = match self.kafka_consumer.parse_message(&message) {
+            Err(e) => (FailureStage::Decode, e),
+            Ok(data_point) => match self
+                .processor
+                .process(data_point, message.partition(), message.offset())
+                .await
+            {
+                Ok(ProcessOutcome::Accepted {
+                    points,
+                    aggregates,
+                    late,
+                }) => {
+                    self.batcher.push(message, points);
+                    self.batcher.push_aggregates(aggregates, late);
+                    return Ok(());
+                }
+                Ok(ProcessOutcome::Rejected(rejected)) => {
+                    self.send_to_dead_letter(
+                        &message,
+                        FailureStage::Validation,
+                        anyhow!(rejected.report.summary()),
+                        Some(&rejected.report),
+                    )
+                    .await?;
+                    self.batcher.push_rejected(&message, rejected);
+                    return Ok(());
+                }
+                Err(e) => (FailureStage::Processing, e),
+            },
+        };
+
+        self.send_to_dead_letter(&message, stage, error, None)
+            .await?;
+        self.batcher.track(&message);
+        Ok(())
+    }
+
+    /// When `flush_batch` is next due: when the current batch must be
+    /// written, or pending offsets stored
+    pub fn deadline(&self) -> Option<Instant> {
+        let store =
+            (!self.pending_offsets.is_empty()).then(|| self.last_stored + self.store_interval);
+        match (self.batcher.deadline(), store) {
+            (Some(batch), Some(store)) => Some(batch.min(store)),
+            (batch, store) => batch.or(store),
+        }
+    }
+
+    /// Write the current batch to InfluxDB, and store the offsets of written
+    /// messages once the store interval has passed. As with `handle_message`,
+    /// an error means a message could not be dead-lettered.
+    pub async fn flush_batch(&mut self) -> Result<()> {
+        let batch = self.batcher.take();
+        let result = if batch.is_empty() {
+            Ok(())
+        } else {
+            self.write_batch(batch).await
+        };
+
+        if self.last_stored.elapsed() >= self.store_interval {
+            if let Err(e) = self.store_offsets().await {
+                error!("Not storing offsets: {:#}", e);
+            }
+        }
+        result
+    }
+
+    /// Checkpoint the aggregation state, then store the pending offsets it
+    /// reflects. Offsets must not get ahead of the saved state, or a restart
+    /// would lose the readings in between, so they stay pending if saving
+    /// fails. Without pending offsets the state has not changed since the
+    /// last save.
+    pub async fn store_offsets(&mut self) -> Result<()> {
+        self.last_stored = Instant::now();
+        if self.pending_offsets.is_empty() {
+            return Ok(());
+        }
+
+        self.processor.save_checkpoint().await?;
+
+        for ((topic, partition), offset) in self.pending_offsets.drain() {
+            let position = MessagePosition {
+                topic,
+                partition,
+                offset,
+            };
+            if let Err(e) = self.kafka_consumer.store_offset(&position) {
+                error!("{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a batch to InfluxDB and queue the offsets it covers for storing.
+    /// If InfluxDB rejects the batch, its messages are retried one by one so
+    /// only the offending ones end up dead-lettered. Offsets stop short of any
+    /// message that could not be dead-lettered either.
+    async fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        let mut failed: HashMap<(String, i32), i64> = HashMap::new();
+        let mut dead_letter_error = None;
+        let result = match self.influx_writer.to_line_protocol(
+            &batch.points,
+            &batch.rejections,
+            &batch.aggregates,
+            &batch.late,
+        ) {
+            Ok(body) => self.write_durably(body).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            warn!(
+                "InfluxDB rejected batch of {} points, writing messages individually: {:#}",
+                batch.points.len(),
+                e
+            );
+            for (message, range) in &batch.messages {
+                let result = match self.influx_writer.to_line_protocol(
+                    &batch.points[range.clone()],
+                    &[],
+                    &[],
+                    &[],
+                ) {
+                    Ok(body) => self.write_durably(body).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    let sent = match dead_letter_error {
+                        // Not waiting on the dead-letter topic again once it failed
+                        Some(_) => Err(e),
+                        None => {
+                            self.send_to_dead_letter(message, FailureStage::Write, e, None)
+                                .await
+                        }
+                    };
+                    if let Err(e) = sent {
+                        let key = (message.topic().to_string(), message.partition());
+                        let offset = failed.entry(key).or_insert(message.offset());
+                        *offset = (*offset).min(message.offset());
+                        dead_letter_error.get_or_insert(e);
+                    }
+                }
+            }
+
+            // The rejected messages themselves were dead-lettered already
+            let result = match self.influx_writer.to_line_protocol(
+                &[],
+                &batch.rejections,
+                &batch.aggregates,
+                &batch.late,
+            ) {
+                Ok(body) => self.write_durably(body).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(
+                    "Failed to write rejections and aggregates to InfluxDB: {:#}",
+                    e
+                );
+            }
+        }
+
+        // Only now are the messages handled, so their offsets may be stored
+        // with the next checkpoint
+        for position in batch.positions(&failed) {
+            let offset = self
+                .pending_offsets
+                .entry((position.topic, position.partition))
+                .or_insert(position.offset);
+            *offset = (*offset).max(position.offset);
+        }
+        dead_letter_error.map_or(Ok(()), Err)
+    }
+
+    /// Write line protocol to InfluxDB, or to the spool if InfluxDB is
+    /// unavailable or still has spooled batches to catch up on, so that
+    /// offsets are never stored ahead of the data. Only a permanent rejection
+    /// of the data is an error.
+    async fn write_durably(&self, body: Vec<u8>) -> Result<()> {
+        if self.spool.is_empty() {
+            match self.influx_writer.write_line_protocol(body.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if influx_writer::is_retryable(&e) => {
+                    warn!("InfluxDB unavailable, spooling batch: {:#}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.spool.append(&body).await
+    }
+
+    /// Dead-letter a message, retrying with backoff while the dead-letter
+    /// topic is unreachable. Without a dead-letter topic the message is
+    /// dropped.
+    async fn send_to_dead_letter(
+        &self,
+        message: &OwnedMessage,
+        stage: FailureStage,
+        error: anyhow::Error,
+        validation: Option<&ValidationReport>,
+    ) -> Result<()> {
+        let reason = format!("{:#}", error);
+        let Some(dead_letter) = &self.dead_letter else {
+            self.metrics
+                .increment("dead_lettered", &[("stage", stage.as_str())]);
+            error!("Dropping message at {} stage: {}", stage.as_str(), reason);
+            return Ok(());
+        };
+
+        let mut backoff = DEAD_LETTER_INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match dead_letter.send(message, stage, &reason, validation).await {
+                Ok(()) => {
+                    self.metrics
+                        .increment("dead_lettered", &[("stage", stage.as_str())]);
+                    return Ok(());
+                }
+                Err(e) if attempt < DEAD_LETTER_ATTEMPTS => {
+                    warn!("{:#}, retrying in {:.1}s", e, backoff.as_secs_f64());
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
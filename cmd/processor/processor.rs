@@ -1,276 +1,245 @@
 use anyhow::Result;
 use std::collections::HashMap;
+use std::sync::Arc;
 use tracing::{debug, info, warn};
 
+use crate::aggregation::{Aggregator, LateReading, WindowAggregate};
+use crate::checkpoint::Checkpoint;
 use crate::config::ProcessingConfig;
 use crate::geo::H3Geocoder;
+use crate::geofence::{GeofenceRegistry, ZoneMatch};
+use crate::metrics::Metrics;
 use crate::proto::DataPoint;
+use crate::units::{OriginalReading, UnitRegistry};
+use crate::validation::{QcFlag, QualityControl, RuleSet, ValidationFailure, ValidationReport};
 
 pub struct DataProcessor {
     config: ProcessingConfig,
     geocoder: H3Geocoder,
+    geofences: GeofenceRegistry,
+    rules: RuleSet,
+    units: UnitRegistry,
+    aggregator: Aggregator,
+    checkpoint: Option<Checkpoint>,
+    metrics: Arc<Metrics>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ProcessedPoint {
     pub data_point: DataPoint,
     pub enriched_data: EnrichedData,
+    pub quality: QualityControl,
+    /// Value and units as received, if they were converted
+    pub original: Option<OriginalReading>,
+}
+
+/// A data point that failed validation, with the reasons why
+#[derive(Debug, Clone)]
+pub struct RejectedPoint {
+    pub data_point: DataPoint,
+    pub report: ValidationReport,
+}
+
+/// Result of running a data point through the pipeline
+#[derive(Debug)]
+pub enum ProcessOutcome {
+    /// The processed points, plus any aggregation windows the point closed
+    /// or corrected, and the windows it came too late for
+    Accepted {
+        points: Vec<ProcessedPoint>,
+        aggregates: Vec<WindowAggregate>,
+        late: Vec<LateReading>,
+    },
+    Rejected(RejectedPoint),
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct EnrichedData {
-    pub country: Option<String>,
-    pub region: Option<String>,
-    pub timezone: Option<String>,
-    pub nearest_place: Option<String>,
-    pub h3_cells: Option<[u64; 9]>, // H3 cell IDs for resolutions 0-8
+    /// ISO2 country code
+    pub country: Option<Arc<str>>,
+    /// Admin1 code
+    pub region: Option<Arc<str>>,
+    pub admin2: Option<Arc<str>>,
+    pub country_name: Option<Arc<str>>,
+    pub country_iso3: Option<Arc<str>>,
+    pub continent: Option<Arc<str>>,
+    pub region_name: Option<Arc<str>>,
+    pub admin2_name: Option<Arc<str>>,
+    pub marine_region: Option<Arc<str>>,
+    /// Geofenced zones containing the point
+    pub zones: Vec<ZoneMatch>,
+    pub timezone: Option<Arc<str>>,
+    pub nearest_place: Option<Arc<str>>,
+    pub nearest_place_distance_km: Option<f64>,
+    pub nearest_place_bearing_deg: Option<f64>,
+    /// Resolution and H3 cell ID for each configured resolution
+    pub h3_cells: Option<Vec<(u8, u64)>>,
     pub resolution_used: Option<u8>,
     pub calculated_fields: HashMap<String, f64>,
 }
 
 impl DataProcessor {
-    pub fn new(config: &ProcessingConfig, geocoder: H3Geocoder) -> Self {
-        DataProcessor {
+    pub fn new(
+        config: &ProcessingConfig,
+        geocoder: H3Geocoder,
+        geofences: GeofenceRegistry,
+        units: UnitRegistry,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let mut aggregator = Aggregator::new(&config.aggregation)?;
+        let checkpoint = match &config.aggregation.checkpoint_path {
+            Some(path) if config.enable_aggregation => Some(Checkpoint::new(path)),
+            _ => None,
+        };
+        if let Some(checkpoint) = &checkpoint {
+            if let Some(state) = checkpoint.load()? {
+                aggregator.restore(state);
+                info!(
+                    "💾 Restored {} open aggregation windows from {}",
+                    aggregator.open_windows(),
+                    checkpoint.path().display()
+                );
+            }
+        }
+
+        Ok(DataProcessor {
             config: config.clone(),
             geocoder,
-        }
+            geofences,
+            rules: RuleSet::new(&config.validation_rules),
+            units,
+            aggregator,
+            checkpoint,
+            metrics,
+        })
     }
 
-    pub async fn process(&self, data_point: DataPoint) -> Result<Vec<ProcessedPoint>> {
-        let mut processed_points = Vec::new();
-
-        // Step 1: Validation
-        if self.config.enable_validation && !self.validate_point(&data_point)? {
+    /// Process a point read from the message at `offset` in `partition`
+    pub async fn process(
+        &mut self,
+        mut data_point: DataPoint,
+        partition: i32,
+        offset: i64,
+    ) -> Result<ProcessOutcome> {
+        // Step 1: Unit normalization
+        let original = self.units.normalize(&mut data_point);
+
+        // Step 2: Validation
+        let mut report = ValidationReport::default();
+        if self.config.enable_validation {
+            self.validate_point(&data_point, &mut report);
+        }
+        // Needed by aggregation whether or not other checks are enabled
+        if self.config.enable_aggregation {
+            self.aggregator.check_event_time(&data_point, &mut report);
+        }
+        self.record_failures(&data_point, &report);
+        if report.is_rejected() {
             warn!("⚠️  Data point failed validation: {:?}", data_point);
-            return Ok(processed_points); // Return empty vec for invalid data
+            return Ok(ProcessOutcome::Rejected(RejectedPoint {
+                data_point,
+                report,
+            }));
         }
+        let quality = report.quality_control(data_point.value);
 
-        // Step 2: Enrichment
+        // Step 3: Enrichment
         let enriched_data = if self.config.enable_enrichment {
             self.enrich_point(&data_point).await?
         } else {
             EnrichedData::default()
         };
 
-        // Step 3: Aggregation (if enabled)
-        if self.config.enable_aggregation {
-            let aggregated_points = self.aggregate_point(&data_point, &enriched_data)?;
-            for point in aggregated_points {
-                processed_points.push(ProcessedPoint {
-                    data_point: point,
-                    enriched_data: enriched_data.clone(),
-                });
-            }
+        // Step 4: Aggregation (if enabled). Only usable values are aggregated.
+        let (aggregates, late) = if self.config.enable_aggregation
+            && matches!(quality.flag, QcFlag::Good | QcFlag::Suspect)
+            && data_point.value.is_finite()
+        {
+            self.aggregate_point(
+                &data_point,
+                enriched_data.timezone.as_deref(),
+                partition,
+                offset,
+            )
         } else {
-            processed_points.push(ProcessedPoint {
+            (Vec::new(), Vec::new())
+        };
+
+        info!("✅ Processed data point ({} aggregates)", aggregates.len());
+        Ok(ProcessOutcome::Accepted {
+            points: vec![ProcessedPoint {
                 data_point,
                 enriched_data,
-            });
-        }
-
-        info!("✅ Processed {} data points", processed_points.len());
-        Ok(processed_points)
+                quality,
+                original,
+            }],
+            aggregates,
+            late,
+        })
     }
 
-    fn validate_point(&self, point: &DataPoint) -> Result<bool> {
+    fn validate_point(&self, point: &DataPoint, report: &mut ValidationReport) {
         debug!(
             "🔍 Validating point: {} ({})",
             point.variable, point.category
         );
 
         // Check for required fields
-        if point.source.is_empty() {
-            return Ok(false);
-        }
-
-        if point.variable.is_empty() {
-            return Ok(false);
-        }
-
-        if point.category.is_empty() {
-            return Ok(false);
-        }
-
-        // Validate based on category and variable type
-        match point.category.as_str() {
-            "environmental" => self.validate_environmental_point(point),
-            "health" => self.validate_health_point(point),
-            "infrastructure" => self.validate_infrastructure_point(point),
-            "economic" => self.validate_economic_point(point),
-            "social" => self.validate_social_point(point),
-            _ => {
-                warn!("Unknown category: {}", point.category);
-                Ok(false)
-            }
-        }
-    }
-
-    fn validate_environmental_point(&self, point: &DataPoint) -> Result<bool> {
-        match point.variable.as_str() {
-            "temperature" => {
-                let rules = &self.config.validation_rules;
-                if point.value < rules.temperature_min || point.value > rules.temperature_max {
-                    warn!(
-                        "Environmental temperature out of range: {:.2}°C",
-                        point.value
-                    );
-                    return Ok(false);
-                }
-            }
-            "humidity" => {
-                let rules = &self.config.validation_rules;
-                if point.value < rules.humidity_min || point.value > rules.humidity_max {
-                    warn!("Environmental humidity out of range: {:.2}%", point.value);
-                    return Ok(false);
-                }
-            }
-            "air_quality" | "pm2.5" | "pm10" => {
-                // Environmental air quality should be non-negative
-                if point.value < 0.0 {
-                    warn!(
-                        "Environmental air quality cannot be negative: {:.2}",
-                        point.value
-                    );
-                    return Ok(false);
-                }
-            }
-            _ => {
-                // For other environmental variables, do basic sanity checks
-                if point.value.is_nan() || point.value.is_infinite() {
-                    return Ok(false);
-                }
+        for (field, value) in [
+            ("source", &point.source),
+            ("variable", &point.variable),
+            ("category", &point.category),
+        ] {
+            if value.is_empty() {
+                report.push(ValidationFailure::new("required", "present", field));
             }
         }
 
-        self.validate_coordinates(point)
-    }
-
-    fn validate_health_point(&self, point: &DataPoint) -> Result<bool> {
-        match point.variable.as_str() {
-            "heart_rate" => {
-                // Health-related heart rate has different ranges
-                if point.value < 30.0 || point.value > 250.0 {
-                    warn!("Health heart rate out of range: {:.2} bpm", point.value);
-                    return Ok(false);
-                }
-            }
-            "temperature" => {
-                // Body temperature has different ranges than environmental
-                if point.value < 35.0 || point.value > 42.0 {
-                    warn!("Health temperature out of range: {:.2}°C", point.value);
-                    return Ok(false);
-                }
-            }
-            _ => {
-                if point.value.is_nan() || point.value.is_infinite() {
-                    return Ok(false);
-                }
-            }
+        if !point.category.is_empty() && !self.rules.knows_category(&point.category) {
+            report.push(ValidationFailure::new("category", "known", "category"));
         }
 
-        self.validate_coordinates(point)
-    }
+        self.units.check(point, report);
 
-    fn validate_infrastructure_point(&self, point: &DataPoint) -> Result<bool> {
-        match point.variable.as_str() {
-            "temperature" => {
-                // Infrastructure temperature can have wider ranges (e.g., machinery)
-                if point.value < -50.0 || point.value > 200.0 {
-                    warn!(
-                        "Infrastructure temperature out of range: {:.2}°C",
-                        point.value
-                    );
-                    return Ok(false);
-                }
-            }
-            "pressure" => {
-                // Infrastructure pressure should be positive
-                if point.value <= 0.0 {
-                    warn!(
-                        "Infrastructure pressure must be positive: {:.2}",
-                        point.value
-                    );
-                    return Ok(false);
-                }
-            }
-            "flow_rate" => {
-                // Flow rate should be non-negative
-                if point.value < 0.0 {
-                    warn!(
-                        "Infrastructure flow rate cannot be negative: {:.2}",
-                        point.value
-                    );
-                    return Ok(false);
-                }
-            }
-            _ => {
-                if point.value.is_nan() || point.value.is_infinite() {
-                    return Ok(false);
-                }
-            }
-        }
+        // Validate the value against the configured rule for this kind of reading
+        self.rules.check(point, report);
 
-        self.validate_coordinates(point)
+        self.validate_coordinates(point, report);
     }
 
-    fn validate_economic_point(&self, point: &DataPoint) -> Result<bool> {
-        match point.variable.as_str() {
-            "price" | "cost" | "revenue" => {
-                // Economic values should generally be non-negative
-                if point.value < 0.0 {
-                    warn!("Economic value cannot be negative: {:.2}", point.value);
-                    return Ok(false);
-                }
-            }
-            _ => {
-                if point.value.is_nan() || point.value.is_infinite() {
-                    return Ok(false);
-                }
-            }
+    fn record_failures(&self, point: &DataPoint, report: &ValidationReport) {
+        for failure in &report.failures {
+            warn!(
+                "{} {} from {}: {}",
+                point.category, point.variable, point.source, failure
+            );
+            self.metrics.increment(
+                "validation_failures",
+                &[
+                    ("rule_id", &failure.rule_id),
+                    ("check", failure.check),
+                    ("severity", failure.severity.as_str()),
+                ],
+            );
         }
-
-        self.validate_coordinates(point)
     }
 
-    fn validate_social_point(&self, point: &DataPoint) -> Result<bool> {
-        match point.variable.as_str() {
-            "population" | "count" => {
-                // Social counts should be non-negative integers
-                if point.value < 0.0 || point.value.fract() != 0.0 {
-                    warn!(
-                        "Social count must be non-negative integer: {:.2}",
-                        point.value
-                    );
-                    return Ok(false);
-                }
-            }
-            "percentage" | "rate" => {
-                // Social percentages should be between 0 and 100
-                if point.value < 0.0 || point.value > 100.0 {
-                    warn!("Social percentage out of range: {:.2}%", point.value);
-                    return Ok(false);
-                }
-            }
-            _ => {
-                if point.value.is_nan() || point.value.is_infinite() {
-                    return Ok(false);
-                }
-            }
+    fn validate_coordinates(&self, point: &DataPoint, report: &mut ValidationReport) {
+        // Validate coordinates (NaN fails the range check too)
+        if !(-90.0..=90.0).contains(&point.lat) {
+            report.push(
+                ValidationFailure::new("coordinates", "range", "lat")
+                    .observed(point.lat)
+                    .expected(Some(-90.0), Some(90.0)),
+            );
         }
-
-        self.validate_coordinates(point)
-    }
-
-    fn validate_coordinates(&self, point: &DataPoint) -> Result<bool> {
-        // Validate coordinates
-        if point.lat < -90.0 || point.lat > 90.0 {
-            return Ok(false);
-        }
-        if point.lon < -180.0 || point.lon > 180.0 {
-            return Ok(false);
+        if !(-180.0..=180.0).contains(&point.lon) {
+            report.push(
+                ValidationFailure::new("coordinates", "range", "lon")
+                    .observed(point.lon)
+                    .expected(Some(-180.0), Some(180.0)),
+            );
         }
-
-        Ok(true)
     }
 
     async fn enrich_point(&self, point: &DataPoint) -> Result<EnrichedData> {
@@ -284,13 +253,23 @@ impl DataProcessor {
             .geocoder
             .get_complete_location_info(point.lat, point.lon)
         {
-            enriched.country = Some(location.country);
-            enriched.region = Some(location.region);
-            enriched.timezone = Some(location.timezone);
-            enriched.nearest_place = Some(location.nearest_place);
+            enriched.country = location.country;
+            enriched.region = Some(location.region).filter(|region| !region.is_empty());
+            enriched.timezone = location.timezone;
+            enriched.nearest_place = location.nearest_place;
+            enriched.nearest_place_distance_km = location.distance_km;
+            enriched.nearest_place_bearing_deg = location.bearing_deg;
             enriched.h3_cells = Some(location.h3_cells);
-            enriched.resolution_used = Some(location.resolution_used);
+            enriched.resolution_used = location.resolution_used;
+            enriched.admin2 = Some(location.admin2).filter(|admin2| !admin2.is_empty());
+            enriched.country_name = location.country_name;
+            enriched.country_iso3 = location.country_iso3;
+            enriched.continent = location.continent;
+            enriched.region_name = location.region_name;
+            enriched.admin2_name = location.admin2_name;
+            enriched.marine_region = location.marine_region;
         }
+        enriched.zones = self.geofences.zones_at(point.lat, point.lon);
 
         // Add calculated fields based on category and variable type
         match point.category.as_str() {
@@ -351,14 +330,57 @@ impl DataProcessor {
     ) {
     }
 
+    /// Add the point to its time windows and return the windows that closed
+    /// or were corrected, along with the windows it was too late for
     fn aggregate_point(
-        &self,
+        &mut self,
         point: &DataPoint,
-        _enriched: &EnrichedData,
-    ) -> Result<Vec<DataPoint>> {
-        // For now, just return the original point
-        // In a real implementation, you'd accumulate points and create hourly/daily aggregates
-        debug!("📊 Aggregating point (placeholder)");
-        Ok(vec![point.clone()])
+        timezone: Option<&str>,
+        partition: i32,
+        offset: i64,
+    ) -> (Vec<WindowAggregate>, Vec<LateReading>) {
+        debug!(
+            "📊 Aggregating point: {} ({})",
+            point.variable, point.category
+        );
+
+        let late = match self.aggregator.add(point, timezone, partition, offset) {
+            Ok(late) => late,
+            Err(e) => {
+                warn!(
+                    "{} {} from {}: {}",
+                    point.category, point.variable, point.source, e
+                );
+                Vec::new()
+            }
+        };
+        for reading in &late {
+            debug!(
+                "Point from {} at {} is {} ms too late for its {} window",
+                point.source, point.epoch_ms, reading.lateness_ms, reading.window
+            );
+            self.metrics
+                .increment("late_points", &[("window", &reading.window)]);
+        }
+
+        let closed = self.aggregator.take_closed();
+        if !closed.is_empty() {
+            info!("📊 Closed {} aggregation windows", closed.len());
+        }
+        (closed, late)
+    }
+
+    /// Save the aggregation state, if checkpointing is enabled. Must succeed
+    /// before the offsets of the messages it reflects are stored.
+    pub async fn save_checkpoint(&self) -> Result<()> {
+        match &self.checkpoint {
+            Some(checkpoint) => checkpoint.save(&self.aggregator.state()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Number of aggregation windows that have not closed yet
+    pub fn open_windows(&self) -> usize {
+        self.aggregator.open_windows()
     }
 }
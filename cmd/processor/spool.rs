@@ -0,0 +1,504 @@
+use anyhow::{anyhow, Context, Result};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{SpoolConfig, SpoolFullPolicy};
+use crate::influx_writer::{self, InfluxWriter};
+
+const SPOOL_EXTENSION: &str = "lp";
+const TEMP_EXTENSION: &str = "tmp";
+const REJECTED_DIR: &str = "rejected";
+
+/// Write-ahead spool of line protocol batches that InfluxDB could not accept.
+///
+/// Each batch is a separate file named by a monotonically increasing sequence
+/// number, so a background task can replay them in order once the server is
+/// reachable again and files left behind by a previous run are picked up on
+/// startup.
+pub struct Spool {
+    directory: PathBuf,
+    max_bytes: u64,
+    full_policy: SpoolFullPolicy,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    state: Mutex<SpoolState>,
+    /// Signalled when a batch is appended
+    appended: Notify,
+    /// Signalled when a batch is removed
+    freed: Notify,
+}
+
+#[derive(Default)]
+struct SpoolState {
+    next_sequence: u64,
+    total_bytes: u64,
+    /// Spooled batches, oldest first, as (sequence, size in bytes)
+    batches: VecDeque<(u64, u64)>,
+}
+
+impl Spool {
+    pub fn open(config: &SpoolConfig) -> Result<Arc<Self>> {
+        let directory = PathBuf::from(&config.directory);
+        std::fs::create_dir_all(&directory)
+            .with_context(|| format!("Failed to create spool directory {}", directory.display()))?;
+
+        let mut batches = Vec::new();
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+
+            if let Some(sequence) = parse_sequence(&path) {
+                batches.push((sequence, path.metadata()?.len()));
+            } else if is_temp_file(&path) {
+                // Leftover from a write interrupted before its rename
+                warn!("Removing incomplete spool file {}", path.display());
+                std::fs::remove_file(&path)?;
+            } else {
+                warn!("Ignoring {}, which is not a spool file", path.display());
+            }
+        }
+        batches.sort_unstable();
+
+        let state = SpoolState {
+            next_sequence: batches.last().map_or(0, |(sequence, _)| sequence + 1),
+            total_bytes: batches.iter().map(|(_, size)| size).sum(),
+            batches: batches.into(),
+        };
+
+        if !state.batches.is_empty() {
+            info!(
+                "💾 Found {} spooled batches ({} bytes) in {}",
+                state.batches.len(),
+                state.total_bytes,
+                directory.display()
+            );
+        }
+
+        Ok(Arc::new(Spool {
+            directory,
+            max_bytes: config.max_bytes,
+            full_policy: config.full_policy,
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(
+                config.max_backoff_ms.max(config.initial_backoff_ms),
+            ),
+            state: Mutex::new(state),
+            appended: Notify::new(),
+            freed: Notify::new(),
+        }))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().batches.is_empty()
+    }
+
+    /// Durably append a batch. Returns once the batch is on disk, waiting for
+    /// replay to free up space first when the spool is full and the policy is
+    /// to block.
+    pub async fn append(&self, body: &[u8]) -> Result<()> {
+        let size = body.len() as u64;
+
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+
+            if let Some(dropped) = self.make_room(size) {
+                self.delete_dropped(dropped).await?;
+                break;
+            }
+
+            warn!(
+                "💾 Spool full ({} bytes), waiting for InfluxDB to catch up",
+                self.max_bytes
+            );
+            freed.await;
+        }
+
+        let sequence = {
+            let mut state = self.state.lock().unwrap();
+            let sequence = state.next_sequence;
+            state.next_sequence += 1;
+            sequence
+        };
+
+        let path = self.batch_path(sequence);
+        let temp_path = path.with_extension(TEMP_EXTENSION);
+        let body = body.to_vec();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut file = std::fs::File::create(&temp_path)?;
+            file.write_all(&body)?;
+            file.sync_all()?;
+            std::fs::rename(&temp_path, &path)?;
+            Ok(())
+        })
+        .await?
+        .context("Failed to write spool file")?;
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.total_bytes += size;
+            state.batches.push_back((sequence, size));
+        }
+        self.appended.notify_one();
+
+        debug!("💾 Spooled batch {} ({} bytes)", sequence, size);
+        Ok(())
+    }
+
+    /// Replay spooled batches to InfluxDB in order, forever
+    pub fn spawn_replay(self: &Arc<Self>, influx_writer: Arc<InfluxWriter>) -> JoinHandle<()> {
+        let spool = Arc::clone(self);
+        tokio::spawn(async move { spool.replay(influx_writer).await })
+    }
+
+    async fn replay(&self, influx_writer: Arc<InfluxWriter>) {
+        self.replay_to(|body| influx_writer.write_line_protocol(body))
+            .await
+    }
+
+    /// Replay loop behind `replay`, writing batches with `write`
+    async fn replay_to<F, Fut>(&self, mut write: F)
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut backoff = self.initial_backoff;
+
+        loop {
+            let front = self.state.lock().unwrap().batches.front().copied();
+            let Some((sequence, _)) = front else {
+                self.appended.notified().await;
+                continue;
+            };
+
+            let path = self.batch_path(sequence);
+            let body = match tokio::fs::read(&path).await {
+                Ok(body) => body,
+                Err(e) => {
+                    // Most likely dropped by the drop-oldest policy meanwhile
+                    debug!("Spooled batch {} is gone: {}", sequence, e);
+                    self.forget(sequence);
+                    continue;
+                }
+            };
+
+            match write(body).await {
+                Ok(()) => {
+                    backoff = self.initial_backoff;
+                    self.remove(sequence, &path).await;
+                }
+                Err(e) if influx_writer::is_retryable(&e) => {
+                    let delay = jittered(backoff);
+                    warn!(
+                        "InfluxDB unavailable, retrying spooled batch {} in {:.1}s: {:#}",
+                        sequence,
+                        delay.as_secs_f64(),
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    backoff = self.next_backoff(backoff);
+                }
+                Err(e) => {
+                    error!(
+                        "InfluxDB rejected spooled batch {}, moving it to {}/: {:#}",
+                        sequence, REJECTED_DIR, e
+                    );
+                    if let Err(e) = self.reject(&path).await {
+                        error!(
+                            "Failed to move rejected spool file {}: {}",
+                            path.display(),
+                            e
+                        );
+                    }
+                    self.forget(sequence);
+                }
+            }
+        }
+    }
+
+    /// Check whether `size` more bytes fit, taking the oldest batches out of
+    /// the spool first if that is the configured policy. Returns the batches
+    /// taken out, whose files still need deleting, or `None` if the batch has
+    /// to wait. An empty spool accepts any batch.
+    fn make_room(&self, size: u64) -> Option<Vec<(u64, u64)>> {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = Vec::new();
+
+        while !state.batches.is_empty() && state.total_bytes + size > self.max_bytes {
+            if self.full_policy == SpoolFullPolicy::Block {
+                return None;
+            }
+
+            let (sequence, size) = state.batches.pop_front().unwrap();
+            state.total_bytes -= size;
+            dropped.push((sequence, size));
+        }
+
+        Some(dropped)
+    }
+
+    /// Delete the files of batches dropped by `make_room`
+    async fn delete_dropped(&self, dropped: Vec<(u64, u64)>) -> Result<()> {
+        for (sequence, size) in dropped {
+            let path = self.batch_path(sequence);
+            match tokio::fs::remove_file(&path).await {
+                // Replay may have written and removed it in the meantime
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(anyhow!(
+                        "Failed to drop spool file {}: {}",
+                        path.display(),
+                        e
+                    ));
+                }
+                _ => {}
+            }
+            warn!(
+                "💾 Spool full, dropped oldest batch {} ({} bytes)",
+                sequence, size
+            );
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, sequence: u64, path: &Path) {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("Failed to remove spool file {}: {}", path.display(), e);
+            }
+        }
+        self.forget(sequence);
+    }
+
+    async fn reject(&self, path: &Path) -> Result<()> {
+        let rejected_dir = self.directory.join(REJECTED_DIR);
+        tokio::fs::create_dir_all(&rejected_dir).await?;
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Spool file has no name"))?;
+        tokio::fs::rename(path, rejected_dir.join(file_name)).await?;
+        Ok(())
+    }
+
+    fn forget(&self, sequence: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state.batches.iter().position(|(s, _)| *s == sequence) {
+            let (_, size) = state.batches.remove(index).unwrap();
+            state.total_bytes -= size;
+        }
+        drop(state);
+        self.freed.notify_waiters();
+    }
+
+    /// The retry delay after `backoff`, doubling up to `max_backoff`
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        (backoff * 2).min(self.max_backoff)
+    }
+
+    fn batch_path(&self, sequence: u64) -> PathBuf {
+        self.directory
+            .join(format!("{:020}.{}", sequence, SPOOL_EXTENSION))
+    }
+}
+
+/// `backoff` plus up to half of it again at random, so that several
+/// processors do not retry in lockstep
+fn jittered(backoff: Duration) -> Duration {
+    let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+    backoff + Duration::from_millis(jitter)
+}
+
+fn parse_sequence(path: &Path) -> Option<u64> {
+    if path.extension()? != SPOOL_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Whether the file is a batch being written, before it is renamed into place
+fn is_temp_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == TEMP_EXTENSION)
+        && path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| !stem.is_empty() && stem.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, max_bytes: u64, full_policy: SpoolFullPolicy) -> SpoolConfig {
+        let directory =
+            std::env::temp_dir().join(format!("emma-spool-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        SpoolConfig {
+            directory: directory.to_string_lossy().into_owned(),
+            max_bytes,
+            full_policy,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        }
+    }
+
+    fn sequences(spool: &Spool) -> Vec<u64> {
+        let state = spool.state.lock().unwrap();
+        state
+            .batches
+            .iter()
+            .map(|(sequence, _)| *sequence)
+            .collect()
+    }
+
+    /// Run `replay_to` until the spool is empty
+    async fn replay_all<F, Fut>(spool: &Spool, write: F)
+    where
+        F: FnMut(Vec<u8>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let drained = async {
+            while !spool.is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::select! {
+            _ = spool.replay_to(write) => unreachable!(),
+            _ = drained => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn open_removes_only_its_own_leftovers() {
+        let config = config("leftovers", 1024, SpoolFullPolicy::Block);
+        let spool = Spool::open(&config).unwrap();
+        spool.append(b"batch").await.unwrap();
+        drop(spool);
+
+        let directory = Path::new(&config.directory);
+        let leftover = directory.join(format!("{:020}.{}", 1, TEMP_EXTENSION));
+        let others = [directory.join("notes.txt"), directory.join("backup.tmp")];
+        for path in others.iter().chain([&leftover]) {
+            std::fs::write(path, b"data").unwrap();
+        }
+
+        let spool = Spool::open(&config).unwrap();
+        assert_eq!(sequences(&spool), [0]);
+        assert!(!leftover.exists());
+        for path in &others {
+            assert!(path.exists(), "{}", path.display());
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_batches_left_by_a_previous_run_in_order() {
+        let config = config("order", 1024, SpoolFullPolicy::Block);
+        let spool = Spool::open(&config).unwrap();
+        for body in [b"first", b"secnd", b"third"] {
+            spool.append(body).await.unwrap();
+        }
+        drop(spool);
+
+        let spool = Spool::open(&config).unwrap();
+        assert_eq!(sequences(&spool), [0, 1, 2]);
+        assert_eq!(spool.state.lock().unwrap().total_bytes, 15);
+
+        let written = Mutex::new(Vec::new());
+        replay_all(&spool, |body| {
+            written.lock().unwrap().push(body);
+            std::future::ready(Ok(()))
+        })
+        .await;
+
+        assert_eq!(
+            written.into_inner().unwrap(),
+            [b"first".to_vec(), b"secnd".to_vec(), b"third".to_vec()]
+        );
+        assert_eq!(std::fs::read_dir(&config.directory).unwrap().count(), 0);
+
+        // Sequence numbers carry on after the replayed batches
+        spool.append(b"fourth").await.unwrap();
+        assert_eq!(sequences(&spool), [3]);
+    }
+
+    #[tokio::test]
+    async fn moves_batches_influxdb_rejects_aside() {
+        let config = config("rejected", 1024, SpoolFullPolicy::Block);
+        let spool = Spool::open(&config).unwrap();
+        spool.append(b"bad line").await.unwrap();
+
+        replay_all(&spool, |_| {
+            std::future::ready(Err(anyhow!("400 Bad Request")))
+        })
+        .await;
+
+        let rejected = Path::new(&config.directory).join(REJECTED_DIR);
+        assert!(rejected.join(format!("{:020}.lp", 0)).is_file());
+        assert!(!spool.batch_path(0).exists());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_discards_batches_beyond_the_byte_limit() {
+        let config = config("drop-oldest", 10, SpoolFullPolicy::DropOldest);
+        let spool = Spool::open(&config).unwrap();
+        spool.append(b"aaaa").await.unwrap();
+        spool.append(b"bbbb").await.unwrap();
+        spool.append(b"cccc").await.unwrap();
+
+        assert_eq!(sequences(&spool), [1, 2]);
+        assert_eq!(spool.state.lock().unwrap().total_bytes, 8);
+        assert!(!spool.batch_path(0).exists());
+        assert_eq!(std::fs::read(spool.batch_path(2)).unwrap(), b"cccc");
+
+        // A batch larger than the whole spool replaces everything else
+        spool.append(&[b'd'; 16]).await.unwrap();
+        assert_eq!(sequences(&spool), [3]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_until_replay_frees_space() {
+        let config = config("block", 8, SpoolFullPolicy::Block);
+        let spool = Spool::open(&config).unwrap();
+        spool.append(b"aaaa").await.unwrap();
+        spool.append(b"bbbb").await.unwrap();
+
+        let full = tokio::time::timeout(Duration::from_millis(50), spool.append(b"cccc")).await;
+        assert!(full.is_err());
+        assert_eq!(sequences(&spool), [0, 1]);
+
+        let append = spool.append(b"cccc");
+        tokio::pin!(append);
+        assert!(futures::poll!(append.as_mut()).is_pending());
+        spool.remove(0, &spool.batch_path(0)).await;
+        append.await.unwrap();
+        assert_eq!(sequences(&spool), [1, 2]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let spool = Spool::open(&config("backoff", 1024, SpoolFullPolicy::Block)).unwrap();
+
+        let mut backoff = spool.initial_backoff;
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            delays.push(backoff.as_millis());
+            backoff = spool.next_backoff(backoff);
+        }
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+
+        for _ in 0..100 {
+            let delay = jittered(Duration::from_millis(100));
+            assert!((100..=150).contains(&delay.as_millis()));
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+use crate::config::{Severity, UnitsConfig, UnknownUnitPolicy};
+use crate::proto::DataPoint;
+use crate::validation::{ValidationFailure, ValidationReport};
+
+/// A unit of measurement: `value * scale + offset` gives the value in the
+/// base unit of its dimension (the one with scale 1 and offset 0).
+#[derive(Debug)]
+struct Unit {
+    name: &'static str,
+    dimension: &'static str,
+    scale: f64,
+    offset: f64,
+    aliases: &'static [&'static str],
+}
+
+const fn unit(
+    name: &'static str,
+    dimension: &'static str,
+    scale: f64,
+    aliases: &'static [&'static str],
+) -> Unit {
+    Unit {
+        name,
+        dimension,
+        scale,
+        offset: 0.0,
+        aliases,
+    }
+}
+
+/// Known units, named as the ingestor sources name them
+const UNITS: &[Unit] = &[
+    // Temperature, base celsius
+    unit(
+        "celsius",
+        "temperature",
+        1.0,
+        &["c", "°c", "degc", "deg_c", "degrees_celsius"],
+    ),
+    Unit {
+        name: "fahrenheit",
+        dimension: "temperature",
+        scale: 5.0 / 9.0,
+        offset: -32.0 * 5.0 / 9.0,
+        aliases: &["f", "°f", "degf", "deg_f", "degrees_fahrenheit"],
+    },
+    Unit {
+        name: "kelvin",
+        dimension: "temperature",
+        scale: 1.0,
+        offset: -273.15,
+        aliases: &["k"],
+    },
+    // Pressure, base hectopascal
+    unit(
+        "hPa",
+        "pressure",
+        1.0,
+        &["hectopascal", "mbar", "millibar", "mb"],
+    ),
+    unit("Pa", "pressure", 0.01, &["pascal"]),
+    unit("kPa", "pressure", 10.0, &["kilopascal"]),
+    unit("bar", "pressure", 1000.0, &[]),
+    unit("atm", "pressure", 1013.25, &["atmosphere"]),
+    unit("psi", "pressure", 68.947_572_93, &[]),
+    unit(
+        "inHg",
+        "pressure",
+        33.863_886_67,
+        &["in_hg", "inches_of_mercury"],
+    ),
+    unit("mmHg", "pressure", 1.333_223_87, &["mm_hg", "torr"]),
+    // Speed, base metres per second
+    unit(
+        "m/s",
+        "speed",
+        1.0,
+        &["mps", "meters_per_second", "metres_per_second"],
+    ),
+    unit(
+        "km/h",
+        "speed",
+        1.0 / 3.6,
+        &["kph", "kmh", "kilometers_per_hour"],
+    ),
+    unit("mph", "speed", 0.447_04, &["miles_per_hour"]),
+    unit("knots", "speed", 0.514_444_44, &["knot", "kn", "kt"]),
+    unit("ft/s", "speed", 0.3048, &["fps", "feet_per_second"]),
+    // Length, base metres
+    unit("meters", "length", 1.0, &["m", "metres", "meter", "metre"]),
+    unit("km", "length", 1000.0, &["kilometers", "kilometres"]),
+    unit("cm", "length", 0.01, &["centimeters", "centimetres"]),
+    unit("mm", "length", 0.001, &["millimeters", "millimetres"]),
+    unit("feet", "length", 0.3048, &["ft", "foot"]),
+    unit("miles", "length", 1609.344, &["mi", "mile"]),
+    // Volumetric flow, base cubic metres per second
+    unit(
+        "m³/s",
+        "flow",
+        1.0,
+        &["m3/s", "cumecs", "cubic_meters_per_second"],
+    ),
+    unit(
+        "m³/h",
+        "flow",
+        1.0 / 3600.0,
+        &["m3/h", "cubic_meters_per_hour"],
+    ),
+    unit(
+        "ft³/s",
+        "flow",
+        0.028_316_846_6,
+        &["ft3/s", "cfs", "cubic_feet_per_second"],
+    ),
+    unit(
+        "l/s",
+        "flow",
+        0.001,
+        &["liters_per_second", "litres_per_second"],
+    ),
+    // Ratios, base percent
+    unit("percentage", "ratio", 1.0, &["%", "percent", "pct"]),
+    unit("fraction", "ratio", 100.0, &["ratio"]),
+    // Money, base US dollars
+    unit("usd", "money", 1.0, &["dollars", "us_dollars"]),
+    unit("thousands_usd", "money", 1e3, &[]),
+    unit("millions_usd", "money", 1e6, &[]),
+    unit("billions_usd", "money", 1e9, &[]),
+    // Concentration, base micrograms per cubic metre
+    unit(
+        "µg/m³",
+        "concentration",
+        1.0,
+        &["ug/m3", "μg/m³", "µg/m3", "micrograms_per_cubic_meter"],
+    ),
+    unit("mg/m³", "concentration", 1000.0, &["mg/m3"]),
+    // Units without conversions
+    unit(
+        "mg/l",
+        "mass_concentration",
+        1.0,
+        &["mg/L", "milligrams_per_liter"],
+    ),
+    unit("bpm", "frequency", 1.0, &["beats_per_minute"]),
+    unit("people", "count", 1.0, &["persons"]),
+    unit("units", "count", 1.0, &["count"]),
+    unit("index_points", "index", 1.0, &["points", "index"]),
+    unit("ms", "duration", 1.0, &["milliseconds"]),
+    unit("s", "duration", 1000.0, &["seconds", "sec"]),
+    unit("minutes", "duration", 60_000.0, &["min"]),
+    unit("kWh", "energy", 1.0, &["kilowatt_hours"]),
+    unit("MWh", "energy", 1000.0, &["megawatt_hours"]),
+];
+
+/// The value and units a reading arrived with, kept when it was converted
+#[derive(Debug, Clone)]
+pub struct OriginalReading {
+    pub value: f64,
+    pub units: String,
+}
+
+/// Parses unit strings and converts readings of the variables that have a
+/// canonical unit into it, so validation and storage see consistent units.
+pub struct UnitRegistry {
+    lookup: HashMap<String, &'static Unit>,
+    canonical: HashMap<String, &'static Unit>,
+    unknown_units: UnknownUnitPolicy,
+}
+
+impl UnitRegistry {
+    pub fn new(config: &UnitsConfig) -> Result<Self> {
+        let mut lookup = HashMap::new();
+        for unit in UNITS {
+            lookup.insert(unit.name.to_lowercase(), unit);
+            for alias in unit.aliases {
+                lookup.insert(alias.to_lowercase(), unit);
+            }
+        }
+
+        for (alias, name) in &config.aliases {
+            let unit = *lookup
+                .get(&name.to_lowercase())
+                .ok_or_else(|| anyhow!("Unit alias {} refers to unknown unit {}", alias, name))?;
+            lookup.insert(alias.to_lowercase(), unit);
+        }
+
+        let mut canonical = HashMap::new();
+        for (variable, name) in &config.canonical {
+            let unit = *lookup.get(&name.to_lowercase()).ok_or_else(|| {
+                anyhow!(
+                    "Canonical unit {} for {} is not a known unit",
+                    name,
+                    variable
+                )
+            })?;
+            canonical.insert(variable.clone(), unit);
+        }
+
+        Ok(UnitRegistry {
+            lookup,
+            canonical,
+            unknown_units: config.unknown_units,
+        })
+    }
+
+    /// Convert the point's value into the canonical unit configured for its
+    /// variable. Variables without one, and units that are unknown or of a
+    /// different dimension, are left as they are.
+    pub fn normalize(&self, point: &mut DataPoint) -> Option<OriginalReading> {
+        let target = *self.canonical.get(&point.variable)?;
+        let unit = self.unit(&point.units)?;
+        if unit.dimension != target.dimension {
+            return None;
+        }
+
+        if std::ptr::eq(unit, target) {
+            // Same unit, possibly spelled differently
+            point.units = target.name.to_string();
+            return None;
+        }
+
+        let original = OriginalReading {
+            value: point.value,
+            units: std::mem::replace(&mut point.units, target.name.to_string()),
+        };
+        point.value = (point.value * unit.scale + unit.offset - target.offset) / target.scale;
+
+        Some(original)
+    }
+
+    /// Report units that are unknown, or that `normalize` could not convert to
+    /// the variable's canonical unit, according to the configured policy
+    pub fn check(&self, point: &DataPoint, report: &mut ValidationReport) {
+        let Some(unit) = self.unit(&point.units) else {
+            self.report_unknown("known", report);
+            return;
+        };
+        if let Some(target) = self.canonical.get(&point.variable) {
+            if target.dimension != unit.dimension {
+                self.report_unknown("compatible", report);
+            }
+        }
+    }
+
+    fn unit(&self, name: &str) -> Option<&'static Unit> {
+        self.lookup.get(&name.trim().to_lowercase()).copied()
+    }
+
+    fn report_unknown(&self, check: &'static str, report: &mut ValidationReport) {
+        let failure = ValidationFailure::new("units", check, "units");
+        match self.unknown_units {
+            UnknownUnitPolicy::Accept => {}
+            UnknownUnitPolicy::Flag => report.push(failure.severity(Severity::Warning)),
+            UnknownUnitPolicy::Reject => report.push(failure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(unknown_units: UnknownUnitPolicy) -> UnitRegistry {
+        let config = UnitsConfig {
+            canonical: [
+                ("temperature", "celsius"),
+                ("pressure", "hPa"),
+                ("wind_speed", "m/s"),
+                ("gdp", "billions_usd"),
+            ]
+            .into_iter()
+            .map(|(variable, unit)| (variable.to_string(), unit.to_string()))
+            .collect(),
+            aliases: [("deg C".to_string(), "celsius".to_string())].into(),
+            unknown_units,
+        };
+        UnitRegistry::new(&config).unwrap()
+    }
+
+    fn point(variable: &str, value: f64, units: &str) -> DataPoint {
+        DataPoint {
+            variable: variable.to_string(),
+            value,
+            units: units.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn normalized(variable: &str, value: f64, units: &str) -> (f64, String) {
+        let mut point = point(variable, value, units);
+        registry(UnknownUnitPolicy::Accept).normalize(&mut point);
+        (point.value, point.units)
+    }
+
+    fn assert_converted(variable: &str, value: f64, units: &str, expected: f64, target: &str) {
+        let (converted, converted_units) = normalized(variable, value, units);
+        assert!(
+            (converted - expected).abs() < 1e-6,
+            "{value} {units} gave {converted}, not {expected}"
+        );
+        assert_eq!(converted_units, target);
+    }
+
+    #[test]
+    fn converts_affine_temperature_units() {
+        assert_converted("temperature", 212.0, "fahrenheit", 100.0, "celsius");
+        assert_converted("temperature", -40.0, "°F", -40.0, "celsius");
+        assert_converted("temperature", 32.0, "F", 0.0, "celsius");
+        assert_converted("temperature", 273.15, "kelvin", 0.0, "celsius");
+    }
+
+    #[test]
+    fn converts_scaled_units() {
+        assert_converted("pressure", 101.325, "kPa", 1013.25, "hPa");
+        assert_converted("pressure", 1.0, "bar", 1000.0, "hPa");
+        assert_converted("wind_speed", 36.0, "km/h", 10.0, "m/s");
+        assert_converted("gdp", 2500.0, "millions_usd", 2.5, "billions_usd");
+    }
+
+    #[test]
+    fn keeps_the_original_reading() {
+        let mut point = point("temperature", 50.0, "fahrenheit");
+        let original = registry(UnknownUnitPolicy::Accept)
+            .normalize(&mut point)
+            .unwrap();
+        assert_eq!(original.value, 50.0);
+        assert_eq!(original.units, "fahrenheit");
+    }
+
+    #[test]
+    fn resolves_aliases_to_the_canonical_spelling() {
+        for units in ["deg C", "DEG C", " °C ", "degrees_celsius", "Celsius"] {
+            let mut point = point("temperature", 20.0, units);
+            let original = registry(UnknownUnitPolicy::Accept).normalize(&mut point);
+            assert!(original.is_none(), "{units} needed no conversion");
+            assert_eq!((point.value, point.units.as_str()), (20.0, "celsius"));
+        }
+    }
+
+    #[test]
+    fn rejects_aliases_of_unknown_units() {
+        let config = UnitsConfig {
+            canonical: HashMap::new(),
+            aliases: [("degrees".to_string(), "rankine".to_string())].into(),
+            unknown_units: UnknownUnitPolicy::Accept,
+        };
+        assert!(UnitRegistry::new(&config).is_err());
+    }
+
+    #[test]
+    fn leaves_variables_without_a_canonical_unit_alone() {
+        assert_eq!(
+            normalized("depth", 12.0, "feet"),
+            (12.0, "feet".to_string())
+        );
+        assert_eq!(
+            normalized("revenue", 3.0, "millions_usd"),
+            (3.0, "millions_usd".to_string())
+        );
+        assert_eq!(normalized("delay", 2.0, "min"), (2.0, "min".to_string()));
+    }
+
+    #[test]
+    fn reports_unknown_and_incompatible_units_by_policy() {
+        let failures = |policy, variable: &str, units: &str| {
+            let point = point(variable, 1.0, units);
+            let mut report = ValidationReport::default();
+            registry(policy).check(&point, &mut report);
+            report.failures
+        };
+
+        assert!(failures(UnknownUnitPolicy::Reject, "temperature", "fahrenheit").is_empty());
+        assert!(failures(UnknownUnitPolicy::Accept, "temperature", "furlongs").is_empty());
+
+        let unknown = failures(UnknownUnitPolicy::Reject, "depth", "furlongs");
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].check, "known");
+        assert_eq!(unknown[0].severity, Severity::Error);
+
+        let incompatible = failures(UnknownUnitPolicy::Flag, "temperature", "hPa");
+        assert_eq!(incompatible.len(), 1);
+        assert_eq!(incompatible[0].check, "compatible");
+        assert_eq!(incompatible[0].severity, Severity::Warning);
+
+        // Incompatible readings are not converted
+        assert_eq!(
+            normalized("temperature", 5.0, "hPa"),
+            (5.0, "hPa".to_string())
+        );
+    }
+}
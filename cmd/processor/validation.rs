@@ -0,0 +1,464 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::config::{FailureAction, Severity, ValidationRule};
+use crate::proto::DataPoint;
+
+const ANY_VARIABLE: &str = "*";
+
+/// A single failed check, with enough context to explain the rejection
+/// without the original log line.
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationFailure {
+    pub rule_id: String,
+    pub check: &'static str,
+    pub field: &'static str,
+    pub observed: Option<f64>,
+    pub expected_min: Option<f64>,
+    pub expected_max: Option<f64>,
+    pub severity: Severity,
+    pub on_failure: FailureAction,
+}
+
+impl ValidationFailure {
+    pub fn new(rule_id: &str, check: &'static str, field: &'static str) -> Self {
+        ValidationFailure {
+            rule_id: rule_id.to_string(),
+            check,
+            field,
+            observed: None,
+            expected_min: None,
+            expected_max: None,
+            severity: Severity::Error,
+            on_failure: FailureAction::Drop,
+        }
+    }
+
+    pub fn observed(mut self, value: f64) -> Self {
+        self.observed = Some(value);
+        self
+    }
+
+    pub fn expected(mut self, min: Option<f64>, max: Option<f64>) -> Self {
+        self.expected_min = min;
+        self.expected_max = max;
+        self
+    }
+
+    pub fn severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn on_failure(mut self, on_failure: FailureAction) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    /// Name of the failed test as reported in quality-control output
+    pub fn test_name(&self) -> String {
+        format!("{}:{}", self.rule_id, self.check)
+    }
+
+    fn drops_point(&self) -> bool {
+        self.severity == Severity::Error && self.on_failure == FailureAction::Drop
+    }
+}
+
+impl fmt::Display for ValidationFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} failed on {}",
+            self.rule_id, self.check, self.field
+        )?;
+        if let Some(observed) = self.observed {
+            write!(f, " = {:.2}", observed)?;
+        }
+        match (self.expected_min, self.expected_max) {
+            (Some(min), Some(max)) => write!(f, ", expected [{:.2}, {:.2}]", min, max)?,
+            (Some(min), None) => write!(f, ", expected >= {:.2}", min)?,
+            (None, Some(max)) => write!(f, ", expected <= {:.2}", max)?,
+            (None, None) => {}
+        }
+        write!(f, " ({})", self.severity.as_str())
+    }
+}
+
+/// Quality-control flag written with every stored point
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QcFlag {
+    #[default]
+    Good,
+    /// Only warning-level checks failed
+    Suspect,
+    /// Error-level checks failed on rules configured to flag rather than drop
+    Bad,
+    /// The value is NaN and its rule allows that
+    Missing,
+}
+
+impl QcFlag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QcFlag::Good => "good",
+            QcFlag::Suspect => "suspect",
+            QcFlag::Bad => "bad",
+            QcFlag::Missing => "missing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QualityControl {
+    pub flag: QcFlag,
+    pub failed_tests: Vec<String>,
+}
+
+/// Outcome of validating a data point. The point is rejected if any
+/// error-level check configured to drop failed; other failures only flag it.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub failures: Vec<ValidationFailure>,
+}
+
+impl ValidationReport {
+    pub fn push(&mut self, failure: ValidationFailure) {
+        self.failures.push(failure);
+    }
+
+    pub fn is_rejected(&self) -> bool {
+        self.failures.iter().any(ValidationFailure::drops_point)
+    }
+
+    /// Quality control for a point that is kept despite any failures
+    pub fn quality_control(&self, value: f64) -> QualityControl {
+        let flag = if self
+            .failures
+            .iter()
+            .any(|failure| failure.severity == Severity::Error)
+        {
+            QcFlag::Bad
+        } else if value.is_nan() {
+            QcFlag::Missing
+        } else if !self.failures.is_empty() {
+            QcFlag::Suspect
+        } else {
+            QcFlag::Good
+        };
+
+        QualityControl {
+            flag,
+            failed_tests: self
+                .failures
+                .iter()
+                .map(ValidationFailure::test_name)
+                .collect(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        self.failures
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Validation rules from config, indexed for lookup by data point
+pub struct RuleSet {
+    rules: Vec<ValidationRule>,
+    categories: HashSet<String>,
+}
+
+impl RuleSet {
+    pub fn new(rules: &[ValidationRule]) -> Self {
+        let rules: Vec<ValidationRule> = rules
+            .iter()
+            .cloned()
+            .map(|mut rule| {
+                if rule.id.is_none() {
+                    rule.id = Some(rule.default_id());
+                }
+                rule
+            })
+            .collect();
+
+        RuleSet {
+            categories: rules.iter().map(|rule| rule.category.clone()).collect(),
+            rules,
+        }
+    }
+
+    /// Categories without any rule are not accepted
+    pub fn knows_category(&self, category: &str) -> bool {
+        self.categories.contains(category)
+    }
+
+    /// The most specific rule matching the point, if any
+    pub fn find(&self, point: &DataPoint) -> Option<&ValidationRule> {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.specificity(point).map(|score| (score, rule)))
+            // Earlier rules win ties
+            .min_by_key(|(score, _)| std::cmp::Reverse(*score))
+            .map(|(_, rule)| rule)
+    }
+
+    /// Check the point's value against its rule. Values without a matching
+    /// rule only need to be finite.
+    pub fn check(&self, point: &DataPoint, report: &mut ValidationReport) {
+        match self.find(point) {
+            Some(rule) => rule.check(point.value, report),
+            None if point.value.is_finite() => {}
+            None => report
+                .push(ValidationFailure::new("default", "finite", "value").observed(point.value)),
+        }
+    }
+}
+
+impl ValidationRule {
+    pub fn id(&self) -> &str {
+        self.id.as_deref().unwrap_or_default()
+    }
+
+    fn default_id(&self) -> String {
+        let mut id = format!("{}.{}", self.category, self.variable);
+        if let Some(source) = &self.source {
+            id.push_str(&format!("@{source}"));
+        }
+        if let Some(units) = &self.units {
+            id.push_str(&format!("[{units}]"));
+        }
+        id
+    }
+
+    /// How specifically this rule targets the point, or `None` if it does not apply
+    fn specificity(&self, point: &DataPoint) -> Option<u8> {
+        if self.category != point.category {
+            return None;
+        }
+
+        let mut score = 0;
+        if self.variable == point.variable {
+            score += 4;
+        } else if self.variable != ANY_VARIABLE {
+            return None;
+        }
+
+        match &self.source {
+            Some(source) if *source == point.source => score += 2,
+            Some(_) => return None,
+            None => {}
+        }
+
+        match &self.units {
+            Some(units) if *units == point.units => score += 1,
+            Some(_) => return None,
+            None => {}
+        }
+
+        Some(score)
+    }
+
+    fn check(&self, value: f64, report: &mut ValidationReport) {
+        let failure = |check| {
+            ValidationFailure::new(self.id(), check, "value")
+                .observed(value)
+                .severity(self.severity)
+                .on_failure(self.on_failure)
+        };
+
+        if value.is_nan() {
+            if !self.allow_nan {
+                report.push(failure("nan"));
+            }
+            return;
+        }
+
+        if value.is_infinite() {
+            report.push(failure("finite"));
+            return;
+        }
+
+        if self.min.is_some_and(|min| value < min) {
+            report.push(failure("min").expected(self.min, self.max));
+        }
+
+        if self.max.is_some_and(|max| value > max) {
+            report.push(failure("max").expected(self.min, self.max));
+        }
+
+        if self.non_negative && value < 0.0 {
+            report.push(failure("non_negative").expected(Some(0.0), None));
+        }
+
+        if self.positive && value <= 0.0 {
+            report.push(failure("positive"));
+        }
+
+        if self.integer_only && value.fract() != 0.0 {
+            report.push(failure("integer_only"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules(rules: serde_json::Value) -> RuleSet {
+        let rules: Vec<ValidationRule> = serde_json::from_value(rules).unwrap();
+        RuleSet::new(&rules)
+    }
+
+    fn point(source: &str, category: &str, variable: &str, units: &str, value: f64) -> DataPoint {
+        DataPoint {
+            source: source.to_string(),
+            category: category.to_string(),
+            variable: variable.to_string(),
+            units: units.to_string(),
+            value,
+            ..Default::default()
+        }
+    }
+
+    fn report(rules: &RuleSet, point: &DataPoint) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        rules.check(point, &mut report);
+        report
+    }
+
+    #[test]
+    fn picks_the_most_specific_matching_rule() {
+        let rules = rules(json!([
+            { "category": "environmental", "variable": "*", "id": "any" },
+            { "category": "environmental", "variable": "temperature", "id": "variable" },
+            { "category": "environmental", "variable": "*", "source": "buoy", "id": "any@buoy" },
+            { "category": "environmental", "variable": "temperature", "units": "kelvin", "id": "kelvin" },
+            { "category": "environmental", "variable": "temperature", "source": "buoy", "id": "buoy" },
+            { "category": "environmental", "variable": "temperature", "id": "shadowed" },
+        ]));
+        let find = |source, variable, units| {
+            rules
+                .find(&point(source, "environmental", variable, units, 1.0))
+                .map(ValidationRule::id)
+        };
+
+        assert_eq!(find("station", "humidity", "percentage"), Some("any"));
+        // The earlier of two equally specific rules wins
+        assert_eq!(find("station", "temperature", "celsius"), Some("variable"));
+        assert_eq!(find("buoy", "humidity", "percentage"), Some("any@buoy"));
+        assert_eq!(find("station", "temperature", "kelvin"), Some("kelvin"));
+        // Source outranks units, and an exact variable outranks either
+        assert_eq!(find("buoy", "temperature", "kelvin"), Some("buoy"));
+        assert!(rules
+            .find(&point("buoy", "health", "temperature", "celsius", 1.0))
+            .is_none());
+    }
+
+    #[test]
+    fn default_ids_name_the_rule_and_its_filters() {
+        let rules = rules(json!([
+            { "category": "infrastructure", "variable": "pressure", "source": "plant", "units": "bar" },
+        ]));
+        let point = point("plant", "infrastructure", "pressure", "bar", -1.0);
+        assert_eq!(
+            rules.find(&point).unwrap().id(),
+            "infrastructure.pressure@plant[bar]"
+        );
+    }
+
+    #[test]
+    fn errors_reject_points_and_warnings_do_not() {
+        let rules = rules(json!([
+            { "category": "health", "variable": "heart_rate", "min": 30.0, "max": 250.0 },
+            { "category": "health", "variable": "steps", "non_negative": true, "severity": "warning" },
+        ]));
+
+        let rejected = report(
+            &rules,
+            &point("watch", "health", "heart_rate", "bpm", 300.0),
+        );
+        assert!(rejected.is_rejected());
+        assert_eq!(rejected.failures[0].rule_id, "health.heart_rate");
+        assert_eq!(rejected.failures[0].check, "max");
+        assert_eq!(rejected.failures[0].observed, Some(300.0));
+        assert_eq!(rejected.failures[0].expected_max, Some(250.0));
+
+        let warned = report(&rules, &point("watch", "health", "steps", "units", -5.0));
+        assert!(!warned.is_rejected());
+        assert_eq!(warned.failures[0].check, "non_negative");
+
+        let good = report(&rules, &point("watch", "health", "heart_rate", "bpm", 60.0));
+        assert!(good.failures.is_empty());
+    }
+
+    #[test]
+    fn flagging_rules_keep_failing_points_with_qc_flags() {
+        let rules = rules(json!([
+            { "category": "health", "variable": "heart_rate", "min": 30.0, "max": 250.0 },
+            { "category": "health", "variable": "temperature", "min": 35.0, "max": 42.0, "on_failure": "flag" },
+            { "category": "health", "variable": "steps", "non_negative": true, "severity": "warning" },
+        ]));
+
+        let dropped = report(
+            &rules,
+            &point("watch", "health", "heart_rate", "bpm", 300.0),
+        );
+        assert!(dropped.is_rejected());
+
+        let flagged = report(
+            &rules,
+            &point("watch", "health", "temperature", "celsius", 45.0),
+        );
+        assert!(!flagged.is_rejected());
+        let quality = flagged.quality_control(45.0);
+        assert_eq!(quality.flag, QcFlag::Bad);
+        assert_eq!(quality.failed_tests, ["health.temperature:max"]);
+
+        let warned = report(&rules, &point("watch", "health", "steps", "units", -5.0));
+        assert_eq!(warned.quality_control(-5.0).flag, QcFlag::Suspect);
+
+        let good = report(&rules, &point("watch", "health", "heart_rate", "bpm", 60.0));
+        assert_eq!(good.quality_control(60.0).flag, QcFlag::Good);
+    }
+
+    #[test]
+    fn nan_values_need_allow_nan() {
+        let rules = rules(json!([
+            { "category": "social", "variable": "population", "integer_only": true },
+            { "category": "social", "variable": "rate", "allow_nan": true },
+        ]));
+
+        let rejected = report(
+            &rules,
+            &point("census", "social", "population", "", f64::NAN),
+        );
+        assert!(rejected.is_rejected());
+        assert_eq!(rejected.failures[0].check, "nan");
+
+        let missing = report(&rules, &point("census", "social", "rate", "", f64::NAN));
+        assert!(missing.failures.is_empty());
+        assert_eq!(missing.quality_control(f64::NAN).flag, QcFlag::Missing);
+
+        let fraction = report(&rules, &point("census", "social", "population", "", 10.5));
+        assert_eq!(fraction.failures[0].check, "integer_only");
+    }
+
+    #[test]
+    fn categories_without_rules_are_unknown() {
+        let rules = rules(json!([
+            { "category": "economic", "variable": "price", "non_negative": true },
+        ]));
+        assert!(rules.knows_category("economic"));
+        assert!(!rules.knows_category("health"));
+
+        // Without a rule a value only has to be finite
+        let point = point("market", "economic", "volume", "units", f64::INFINITY);
+        assert_eq!(report(&rules, &point).failures[0].rule_id, "default");
+    }
+}
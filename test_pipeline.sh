@@ -245,13 +245,13 @@ EOF
     log "🦀 Starting Rust processor..."
     
     # Set up processor environment
-    export PROCESSOR_KAFKA_BOOTSTRAP_SERVERS="localhost:9092"
-    export PROCESSOR_KAFKA_TOPIC="data.raw"
-    export PROCESSOR_INFLUXDB_HOST="localhost"
-    export PROCESSOR_INFLUXDB_PORT="8086"
-    export PROCESSOR_INFLUXDB_ORG="emma"
-    export PROCESSOR_INFLUXDB_BUCKET="climate"
-    export PROCESSOR_INFLUXDB_TOKEN="emma-token"
+    export PROCESSOR_KAFKA__BOOTSTRAP_SERVERS="localhost:9092"
+    export PROCESSOR_KAFKA__TOPIC="data.raw"
+    export PROCESSOR_INFLUXDB__HOST="localhost"
+    export PROCESSOR_INFLUXDB__PORT="8086"
+    export PROCESSOR_INFLUXDB__ORG="emma"
+    export PROCESSOR_INFLUXDB__BUCKET="climate"
+    export PROCESSOR_INFLUXDB__TOKEN="emma-token"
     
     # Build processor
     log "Building Rust processor..."